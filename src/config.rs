@@ -0,0 +1,334 @@
+//! Layered configuration files, supplying values for arguments that weren't
+//! given on the command line or through the environment.
+//!
+//! Files are TOML or JSON (chosen by the `.json` extension), keyed by field
+//! name with a nested table for each subcommand:
+//!
+//! ```toml
+//! port = 8080
+//!
+//! [remote.add]
+//! url = "https://example.com"
+//! ```
+//!
+//! Files loaded later override those loaded earlier. A `Config` is used by
+//! adding it as a layer of the parsing `Context`:
+//!
+//! ```rust,ignore
+//! let mut ctx = Context::new();
+//! ctx.add_layer(Config::from_files(&["/etc/myapp.toml", "myapp.toml"])?);
+//! let app = MyApp::try_parse_from_with(env::args_os(), &ctx)?;
+//! ```
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{ self, Read };
+use std::path::{ Path, PathBuf };
+
+use serde_json;
+use toml;
+
+use context::Layer;
+
+#[derive(Debug)]
+pub struct Error {
+    file: PathBuf,
+    message: String,
+}
+
+pub struct Config {
+    files: Vec<Loaded>,
+}
+
+struct Loaded {
+    file: PathBuf,
+    root: BTreeMap<String, Value>,
+}
+
+enum Value {
+    Scalar(String),
+    List(Vec<String>),
+    Table(BTreeMap<String, Value>),
+}
+
+/// A value found in one of the config files.
+pub struct Found<'a> {
+    pub file: &'a Path,
+    /// The full dotted key, including any subcommand tables.
+    pub key: String,
+    pub values: Vec<String>,
+}
+
+impl Error {
+    fn new<S>(file: &Path, message: S) -> Error where S: Into<String> {
+        Error { file: file.to_owned(), message: message.into() }
+    }
+
+    fn io(file: &Path, err: io::Error) -> Error {
+        Error::new(file, err.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to load config file '{}': {}", self.file.display(), self.message)
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config { files: Vec::new() }
+    }
+
+    /// Loads the given files in order, each overriding the ones before it.
+    pub fn from_files<I, P>(files: I) -> Result<Config, Error> where I: IntoIterator<Item=P>, P: AsRef<Path> {
+        let mut config = Config::new();
+        for file in files {
+            config.load(file)?;
+        }
+        Ok(config)
+    }
+
+    /// Loads another file, overriding any values already loaded.
+    pub fn load<P>(&mut self, file: P) -> Result<(), Error> where P: AsRef<Path> {
+        let file = file.as_ref();
+        let mut contents = String::new();
+        File::open(file)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| Error::io(file, e))?;
+
+        let is_json = file.extension().map_or(false, |ext| ext == "json");
+        let root = if is_json { from_json(file, &contents)? } else { from_toml(file, &contents)? };
+
+        self.files.push(Loaded { file: file.to_owned(), root: root });
+        Ok(())
+    }
+
+    /// Finds the value for `key` within the tables named by `path`, from the
+    /// last file that has it.
+    pub fn lookup(&self, path: &[String], key: &str) -> Option<Found> {
+        self.files.iter().rev()
+            .filter_map(|loaded| {
+                let mut table = &loaded.root;
+                for name in path {
+                    match table.get(name) {
+                        Some(&Value::Table(ref inner)) => table = inner,
+                        _ => return None,
+                    }
+                }
+                let values = match table.get(key) {
+                    Some(&Value::Scalar(ref value)) => vec![value.clone()],
+                    Some(&Value::List(ref values)) => values.clone(),
+                    Some(&Value::Table(_)) | None => return None,
+                };
+                let mut full_key = path.join(".");
+                if !full_key.is_empty() {
+                    full_key.push('.');
+                }
+                full_key.push_str(key);
+                Some(Found { file: &loaded.file, key: full_key, values: values })
+            })
+            .next()
+    }
+}
+
+impl Layer for Config {
+    fn lookup(&self, path: &[String], key: &str) -> Option<(Vec<String>, String)> {
+        Config::lookup(self, path, key).map(|found| {
+            let source = format!("config file '{}' key '{}'", found.file.display(), found.key);
+            (found.values, source)
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+fn from_toml(file: &Path, contents: &str) -> Result<BTreeMap<String, Value>, Error> {
+    let mut parser = toml::Parser::new(contents);
+    match parser.parse() {
+        Some(table) => convert_toml_table(file, "", table),
+        None => {
+            let message = parser.errors.iter()
+                .map(|e| {
+                    let (line, col) = parser.to_linecol(e.lo);
+                    format!("{} at line {} column {}", e.desc, line + 1, col + 1)
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            Err(Error::new(file, message))
+        }
+    }
+}
+
+fn convert_toml_table(file: &Path, prefix: &str, table: toml::Table) -> Result<BTreeMap<String, Value>, Error> {
+    let mut values = BTreeMap::new();
+    for (key, value) in table {
+        let full_key = prefix.to_owned() + &key;
+        let value = match value {
+            toml::Value::Table(table) => Value::Table(convert_toml_table(file, &(full_key + "."), table)?),
+            toml::Value::Array(array) => {
+                let mut list = Vec::new();
+                for value in array {
+                    list.push(toml_scalar(file, &full_key, value)?);
+                }
+                Value::List(list)
+            }
+            value => Value::Scalar(toml_scalar(file, &full_key, value)?),
+        };
+        values.insert(key, value);
+    }
+    Ok(values)
+}
+
+fn toml_scalar(file: &Path, key: &str, value: toml::Value) -> Result<String, Error> {
+    match value {
+        toml::Value::String(value) | toml::Value::Datetime(value) => Ok(value),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => {
+            Err(Error::new(file, format!("unsupported nested value for key '{}'", key)))
+        }
+    }
+}
+
+fn from_json(file: &Path, contents: &str) -> Result<BTreeMap<String, Value>, Error> {
+    match serde_json::from_str(contents) {
+        Ok(serde_json::Value::Object(object)) => convert_json_object(file, "", object),
+        Ok(_) => Err(Error::new(file, "expected an object at the top level")),
+        Err(e) => Err(Error::new(file, e.to_string())),
+    }
+}
+
+fn convert_json_object(file: &Path, prefix: &str, object: serde_json::Map<String, serde_json::Value>) -> Result<BTreeMap<String, Value>, Error> {
+    let mut values = BTreeMap::new();
+    for (key, value) in object {
+        let full_key = prefix.to_owned() + &key;
+        let value = match value {
+            // A null is treated the same as the key not being given
+            serde_json::Value::Null => continue,
+            serde_json::Value::Object(object) => Value::Table(convert_json_object(file, &(full_key + "."), object)?),
+            serde_json::Value::Array(array) => {
+                let mut list = Vec::new();
+                for value in array {
+                    list.push(json_scalar(file, &full_key, value)?);
+                }
+                Value::List(list)
+            }
+            value => Value::Scalar(json_scalar(file, &full_key, value)?),
+        };
+        values.insert(key, value);
+    }
+    Ok(values)
+}
+
+fn json_scalar(file: &Path, key: &str, value: serde_json::Value) -> Result<String, Error> {
+    match value {
+        serde_json::Value::String(value) => Ok(value),
+        serde_json::Value::Bool(value) => Ok(value.to_string()),
+        serde_json::Value::I64(value) => Ok(value.to_string()),
+        serde_json::Value::U64(value) => Ok(value.to_string()),
+        serde_json::Value::F64(value) => Ok(value.to_string()),
+        serde_json::Value::Null | serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+            Err(Error::new(file, format!("unsupported nested value for key '{}'", key)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{ self, File };
+    use std::io::Write;
+    use std::path::PathBuf;
+    use context::Layer;
+    use super::Config;
+
+    /// A config file in the temporary directory holding `contents`.
+    fn file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("stomp-config-{}", name));
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    fn path(names: &[&str]) -> Vec<String> {
+        names.iter().map(|&name| name.to_owned()).collect()
+    }
+
+    #[test]
+    fn toml_tables_nest_per_subcommand() {
+        let toml = file("nested.toml", "port = 8080\n[remote.add]\nurl = \"https://example.com\"\ntags = [\"a\", \"b\"]\n");
+        let config = Config::from_files(&[&toml]).unwrap();
+        assert_eq!(config.lookup(&[], "port").unwrap().values, vec!["8080"]);
+        let found = config.lookup(&path(&["remote", "add"]), "url").unwrap();
+        assert_eq!(found.key, "remote.add.url");
+        assert_eq!(found.values, vec!["https://example.com"]);
+        assert_eq!(config.lookup(&path(&["remote", "add"]), "tags").unwrap().values, vec!["a", "b"]);
+        assert!(config.lookup(&[], "url").is_none());
+        assert!(config.lookup(&path(&["remote"]), "add").is_none());
+        fs::remove_file(&toml).unwrap();
+    }
+
+    #[test]
+    fn json_objects_nest_per_subcommand() {
+        let json = file("nested.json", r#"{"port": 8080, "verbose": true, "remote": {"add": {"url": "https://example.com", "name": null}}}"#);
+        let config = Config::from_files(&[&json]).unwrap();
+        assert_eq!(config.lookup(&[], "port").unwrap().values, vec!["8080"]);
+        assert_eq!(config.lookup(&[], "verbose").unwrap().values, vec!["true"]);
+        assert_eq!(config.lookup(&path(&["remote", "add"]), "url").unwrap().values, vec!["https://example.com"]);
+        assert!(config.lookup(&path(&["remote", "add"]), "name").is_none());
+        fs::remove_file(&json).unwrap();
+    }
+
+    #[test]
+    fn later_files_take_precedence() {
+        let first = file("first.toml", "port = 1\nhost = \"a\"\n");
+        let second = file("second.json", r#"{"port": 2}"#);
+        let config = Config::from_files(&[&first, &second]).unwrap();
+        let found = config.lookup(&[], "port").unwrap();
+        assert_eq!(found.values, vec!["2"]);
+        assert_eq!(found.file, second.as_path());
+        assert_eq!(config.lookup(&[], "host").unwrap().values, vec!["a"]);
+        let (_, source) = Layer::lookup(&config, &[], "port").unwrap();
+        assert_eq!(source, format!("config file '{}' key 'port'", second.display()));
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+    }
+
+    #[test]
+    fn errors_name_the_file_and_key() {
+        let toml = file("bad-nested.toml", "[remote]\nurls = [[1]]\n");
+        let message = Config::from_files(&[&toml]).err().unwrap().to_string();
+        assert!(message.contains(&toml.display().to_string()), "{}", message);
+        assert!(message.contains("'remote.urls'"), "{}", message);
+        fs::remove_file(&toml).unwrap();
+
+        let json = file("bad-nested.json", r#"{"remote": {"urls": [{"a": 1}]}}"#);
+        let message = Config::from_files(&[&json]).err().unwrap().to_string();
+        assert!(message.contains(&json.display().to_string()), "{}", message);
+        assert!(message.contains("'remote.urls'"), "{}", message);
+        fs::remove_file(&json).unwrap();
+
+        let syntax = file("bad-syntax.toml", "port = \n");
+        let message = Config::from_files(&[&syntax]).err().unwrap().to_string();
+        assert!(message.contains(&syntax.display().to_string()), "{}", message);
+        assert!(message.contains("line 1"), "{}", message);
+        fs::remove_file(&syntax).unwrap();
+
+        let missing = env::temp_dir().join("stomp-config-missing.toml");
+        let message = Config::from_files(&[&missing]).err().unwrap().to_string();
+        assert!(message.contains(&missing.display().to_string()), "{}", message);
+    }
+}
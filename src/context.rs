@@ -0,0 +1,371 @@
+use std::cell::{ Cell, RefCell };
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use clap::{ self, ArgMatches, ErrorKind };
+
+use sources::{ Entry, Source, Sources };
+
+thread_local! {
+    static RELAX_REQUIRED: Cell<bool> = Cell::new(false);
+    static EXPLAINING: Cell<bool> = Cell::new(false);
+}
+
+/// A source of values for arguments that weren't given on the command line
+/// or in the environment, such as config files.
+pub trait Layer {
+    /// Finds the values for the field `key` within the subcommands named by
+    /// `path`, along with a description of where they came from.
+    fn lookup(&self, path: &[String], key: &str) -> Option<(Vec<String>, String)>;
+}
+
+/// Default values supplied at runtime, e.g. computed from `#[stomp(early)]`
+/// arguments, overriding any `default_value` from the command.
+pub struct Defaults {
+    values: HashMap<Vec<String>, Vec<String>>,
+}
+
+/// Where values not given on the command line are looked up while parsing.
+///
+/// The precedence is command line, then environment, then each layer in the
+/// order they were added, then any `default_value`.
+#[derive(Clone)]
+pub struct Context {
+    env: Option<Rc<HashMap<String, String>>>,
+    layers: Vec<Rc<Layer>>,
+    /// Whether we're only explaining where values come from, so nothing is
+    /// required.
+    explaining: bool,
+    /// The subcommands we're currently within.
+    path: Vec<String>,
+    /// The subcommands whose raw arguments we're within, which is shorter
+    /// than `path` inside a default subcommand.
+    scope: Vec<String>,
+    /// Shared with every subcommand's context.
+    sources: Rc<RefCell<Sources>>,
+    /// Options split out for each `#[stomp(unknown)]` field before parsing,
+    /// by the path to its command.
+    unknown: Rc<RefCell<HashMap<Vec<String>, Vec<OsString>>>>,
+    /// The raw arguments of each command given, for `#[stomp(sequence)]`
+    /// fields that need the order of different options. Only known when
+    /// parsing through `ParseApp`.
+    scopes: Rc<RefCell<Option<Vec<(Vec<String>, Vec<OsString>)>>>>,
+}
+
+/// A value for an argument found outside of the command line.
+#[doc(hidden)]
+pub struct Fallback {
+    values: Vec<String>,
+    source: Source,
+}
+
+impl Context {
+    /// A context that uses the process environment.
+    pub fn new() -> Context {
+        Context {
+            env: None,
+            layers: Vec::new(),
+            explaining: false,
+            path: Vec::new(),
+            scope: Vec::new(),
+            sources: Rc::new(RefCell::new(Sources::new())),
+            unknown: Rc::new(RefCell::new(HashMap::new())),
+            scopes: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// A context that uses the given variables instead of the process
+    /// environment.
+    pub fn with_env(env: HashMap<String, String>) -> Context {
+        Context { env: Some(Rc::new(env)), ..Context::new() }
+    }
+
+    /// Adds a layer of values below any already added.
+    pub fn add_layer<L>(&mut self, layer: L) where L: Layer + 'static {
+        self.layers.push(Rc::new(layer));
+    }
+
+    /// Looks up an environment variable, variables that aren't valid unicode
+    /// are treated as unset.
+    pub fn env(&self, name: &str) -> Option<String> {
+        match self.env {
+            Some(ref env) => env.get(name).cloned(),
+            None => env::var(name).ok(),
+        }
+    }
+
+    /// Where the value of every field parsed with this context came from.
+    pub fn sources(&self) -> Sources {
+        self.sources.borrow().clone()
+    }
+
+    /// A context for `--explain-config`, where nothing is required so it
+    /// works when values are missing.
+    #[doc(hidden)]
+    pub fn explaining(&self) -> Context {
+        Context { explaining: true, ..self.clone() }
+    }
+
+    /// The context for parsing the named subcommand.
+    #[doc(hidden)]
+    pub fn subcommand(&self, name: &str) -> Context {
+        let mut ctx = self.clone();
+        ctx.path.push(name.to_owned());
+        ctx.scope.push(name.to_owned());
+        ctx
+    }
+
+    /// The context for parsing a default subcommand, whose arguments were
+    /// merged into ours.
+    #[doc(hidden)]
+    pub fn default_subcommand(&self, name: &str) -> Context {
+        let mut ctx = self.clone();
+        ctx.path.push(name.to_owned());
+        ctx
+    }
+
+    /// Runs `f` to build and match a command for this context.
+    ///
+    /// When there are layers any required argument could be supplied by
+    /// them, so that gets checked when parsing instead of by clap.
+    #[doc(hidden)]
+    pub fn build<F, R>(&self, f: F) -> R where F: FnOnce() -> R {
+        let previous = (RELAX_REQUIRED.with(|r| r.get()), EXPLAINING.with(|e| e.get()));
+        RELAX_REQUIRED.with(|r| r.set(self.explaining || !self.layers.is_empty()));
+        EXPLAINING.with(|e| e.set(self.explaining));
+        let result = f();
+        RELAX_REQUIRED.with(|r| r.set(previous.0));
+        EXPLAINING.with(|e| e.set(previous.1));
+        result
+    }
+
+    /// Finds the value for the field `key` from the environment variable `env`
+    /// or the layers.
+    #[doc(hidden)]
+    pub fn fallback(&self, key: &str, env: Option<&str>) -> Option<Fallback> {
+        if let Some(var) = env {
+            if let Some(value) = self.env(var) {
+                return Some(Fallback {
+                    values: vec![value],
+                    source: Source::Environment(var.to_owned()),
+                });
+            }
+        }
+
+        self.layers.iter()
+            .filter_map(|layer| layer.lookup(&self.path, key))
+            .next()
+            .map(|(values, source)| Fallback { values: values, source: Source::Layer(source) })
+    }
+
+    /// Keeps the unknown options split out from the arguments until they're
+    /// parsed.
+    #[doc(hidden)]
+    pub fn set_unknown(&self, unknown: HashMap<Vec<String>, Vec<OsString>>) {
+        *self.unknown.borrow_mut() = unknown;
+    }
+
+    /// The unknown options given to the command whose raw arguments we're
+    /// within, for a default subcommand those given to its parent.
+    #[doc(hidden)]
+    pub fn take_unknown(&self) -> Vec<OsString> {
+        self.unknown.borrow_mut().remove(&self.scope).unwrap_or_else(Vec::new)
+    }
+
+    #[doc(hidden)]
+    pub fn set_scopes(&self, scopes: Vec<(Vec<String>, Vec<OsString>)>) {
+        *self.scopes.borrow_mut() = Some(scopes);
+    }
+
+    /// The raw arguments of the command we're within, starting with its
+    /// name and ending where its subcommand starts.
+    #[doc(hidden)]
+    pub fn args(&self) -> Option<Vec<OsString>> {
+        self.scopes.borrow().as_ref().map(|scopes| {
+            scopes.iter()
+                .find(|&&(ref path, _)| *path == self.scope)
+                .map(|&(_, ref args)| args.clone())
+                .unwrap_or_else(Vec::new)
+        })
+    }
+
+    /// Records the source of the field `key`.
+    #[doc(hidden)]
+    pub fn record(&self, key: &str, source: Source, values: Vec<String>) {
+        self.sources.borrow_mut().record(Entry {
+            path: self.path.clone(),
+            field: key.to_owned(),
+            values: values,
+            source: source,
+        });
+    }
+
+    /// Records the source of the field `key` whose value came from `matches`,
+    /// either the command line or clap's default value.
+    #[doc(hidden)]
+    pub fn record_matches(&self, key: &str, matches: &ArgMatches, name: &str) {
+        let occurrences = matches.occurrences_of(name);
+        let values = match matches.values_of_lossy(name) {
+            Some(values) => values,
+            None if occurrences > 0 => vec![occurrences.to_string()],
+            None => Vec::new(),
+        };
+        let source = if occurrences > 0 {
+            Source::CommandLine
+        } else if matches.is_present(name) {
+            Source::Default
+        } else {
+            Source::Unset
+        };
+        self.record(key, source, values);
+    }
+
+    /// Records the source of the field `key` whose value came from `fallback`.
+    #[doc(hidden)]
+    pub fn record_fallback(&self, key: &str, fallback: &Fallback) {
+        self.record(key, fallback.source.clone(), fallback.values.clone());
+    }
+
+    /// Whether the field `key` of the command being parsed was given on the
+    /// command line, in the environment or by a layer, for checking the
+    /// constraints between arguments that clap only sees the command line
+    /// for. Flags and counters set to something false don't count.
+    #[doc(hidden)]
+    pub fn is_given(&self, key: &str, takes_value: bool) -> bool {
+        let path: Vec<&str> = self.path.iter().map(|name| &name[..]).collect();
+        let sources = self.sources.borrow();
+        match sources.get(&path, key) {
+            Some(&Entry { source: Source::Default, .. }) |
+            Some(&Entry { source: Source::Unset, .. }) |
+            None => false,
+            Some(_) if takes_value => true,
+            Some(entry) => entry.values.iter().any(|value| value != "0" && parse_flag(value) != Some(false)),
+        }
+    }
+
+    /// Whether the field `key` was given with the value `value`, for
+    /// `requires_if`.
+    #[doc(hidden)]
+    pub fn is_given_as(&self, key: &str, value: &str) -> bool {
+        let path: Vec<&str> = self.path.iter().map(|name| &name[..]).collect();
+        self.is_given(key, true) && self.sources.borrow().get(&path, key)
+            .map_or(false, |entry| entry.values.iter().any(|v| v == value))
+    }
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context::new()
+    }
+}
+
+impl Defaults {
+    pub fn new() -> Defaults {
+        Defaults { values: HashMap::new() }
+    }
+
+    /// Sets the default for the field `key` of the top level command.
+    pub fn set<V>(&mut self, key: &str, value: V) where V: Into<String> {
+        self.set_all(&[], key, vec![value.into()]);
+    }
+
+    /// Sets the defaults for the field `key` within the subcommands named by
+    /// `path`, multiple values are only allowed for multi-valued fields.
+    pub fn set_all(&mut self, path: &[&str], key: &str, values: Vec<String>) {
+        let mut full_key: Vec<String> = path.iter().map(|&name| name.to_owned()).collect();
+        full_key.push(key.to_owned());
+        self.values.insert(full_key, values);
+    }
+}
+
+impl Default for Defaults {
+    fn default() -> Defaults {
+        Defaults::new()
+    }
+}
+
+impl Layer for Defaults {
+    fn lookup(&self, path: &[String], key: &str) -> Option<(Vec<String>, String)> {
+        let mut full_key = path.to_vec();
+        full_key.push(key.to_owned());
+        self.values.get(&full_key).map(|values| (values.clone(), "runtime defaults".to_owned()))
+    }
+}
+
+impl Fallback {
+    /// Parses the only value, for single valued arguments.
+    pub fn single<T>(&self, name: &str) -> clap::Result<T> where T: FromStr, T::Err: Display {
+        if self.values.len() != 1 {
+            return Err(clap::Error::with_description(
+                &format!("expected a single value for argument '{}' from {} but found {}", name, self.source, self.values.len()),
+                ErrorKind::WrongNumberOfValues));
+        }
+        self.parse(name, &self.values[0])
+    }
+
+    /// Runs the argument's validator on every value.
+    pub fn check<F>(&self, validator: F) -> clap::Result<()> where F: Fn(String) -> Result<(), String> {
+        for value in &self.values {
+            if let Err(e) = validator(value.clone()) {
+                return Err(clap::Error::with_description(
+                    &format!("{} (from {})", e, self.source),
+                    ErrorKind::ValueValidation));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the only value of a flag, which can be any of the usual ways of
+    /// writing true or false in the environment, like `1` or `no`.
+    pub fn flag(&self, name: &str) -> clap::Result<bool> {
+        if self.values.len() != 1 {
+            return Err(clap::Error::with_description(
+                &format!("expected a single value for argument '{}' from {} but found {}", name, self.source, self.values.len()),
+                ErrorKind::WrongNumberOfValues));
+        }
+        parse_flag(&self.values[0]).ok_or_else(|| {
+            clap::Error::with_description(
+                &format!("failed to parse value {:?} for argument '{}' from {}: expected true or false", self.values[0], name, self.source),
+                ErrorKind::ValueValidation)
+        })
+    }
+
+    /// Parses all values, for multi-valued arguments.
+    pub fn all<T>(&self, name: &str) -> clap::Result<Vec<T>> where T: FromStr, T::Err: Display {
+        self.values.iter().map(|value| self.parse(name, value)).collect()
+    }
+
+    fn parse<T>(&self, name: &str, value: &str) -> clap::Result<T> where T: FromStr, T::Err: Display {
+        value.parse().map_err(|e| {
+            clap::Error::with_description(
+                &format!("failed to parse value {:?} for argument '{}' from {}: {}", value, name, self.source, e),
+                ErrorKind::ValueValidation)
+        })
+    }
+}
+
+fn parse_flag(value: &str) -> Option<bool> {
+    match &value.to_lowercase()[..] {
+        "true" | "1" | "yes" | "y" | "on" => Some(true),
+        "false" | "0" | "no" | "n" | "off" | "" => Some(false),
+        _ => None,
+    }
+}
+
+/// Whether the command being built should leave required arguments to be
+/// checked when parsing.
+#[doc(hidden)]
+pub fn relax_required() -> bool {
+    RELAX_REQUIRED.with(|r| r.get())
+}
+
+/// Whether the command being built is only for `--explain-config`, so groups
+/// and modes shouldn't be required either.
+#[doc(hidden)]
+pub fn explaining() -> bool {
+    EXPLAINING.with(|e| e.get())
+}
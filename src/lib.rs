@@ -1,24 +1,247 @@
 extern crate clap;
+extern crate regex;
+#[cfg(feature = "config")]
+extern crate serde_json;
+#[cfg(feature = "config")]
+extern crate toml;
 
-use clap::{ App, ArgMatches };
+mod context;
+mod scan;
+mod sources;
+pub mod types;
+pub mod values;
+#[cfg(feature = "config")]
+pub mod config;
 
-pub trait StompCommand {
+use std::cell::{ Cell, RefCell };
+use std::collections::{ HashMap, HashSet };
+use std::env;
+use std::ffi::{ OsStr, OsString };
+use std::fmt;
+use std::io::{ self, Write };
+use std::mem;
+
+use clap::{ App, AppSettings, Arg, ArgGroup, ArgMatches, ErrorKind };
+
+pub use context::{ Context, Defaults, Layer };
+pub use sources::{ Entry, Source, Sources };
+#[doc(hidden)]
+pub use context::{ explaining, relax_required };
+#[doc(hidden)]
+pub use scan::{ ArgSpec, CommandSpec, SubcommandSpec };
+
+const CHAIN: &'static str = "stomp-chain";
+const EXPLAIN_CONFIG: &'static str = "explain-config";
+
+thread_local! {
+    static WARNED: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+    static LONG_HELP: Cell<bool> = Cell::new(false);
+    static INTERNED: RefCell<HashSet<&'static str>> = RefCell::new(HashSet::new());
+    static CACHED: RefCell<HashMap<(&'static str, bool), &'static str>> = RefCell::new(HashMap::new());
+    static RESERVED_SHORTS: RefCell<Vec<char>> = RefCell::new(Vec::new());
+}
+
+pub trait StompCommand: Sized {
+    /// The source of each field, derived as `<Command>Sources` with
+    /// `#[stomp(sources)]` and otherwise just the `Sources`.
+    type Sources;
     fn command() -> App<'static, 'static>;
-    fn parse(matches: &ArgMatches) -> Self;
+    fn args() -> Vec<Arg<'static, 'static>>;
+    /// The groups of the arguments, including one for each mode.
+    fn groups() -> Vec<ArgGroup<'static>>;
+    /// The `#[stomp(early)]` arguments, derived as `<Command>Early` when
+    /// there are any and otherwise `()`.
+    type Early;
+    /// A command with only the `#[stomp(early)]` arguments, none required.
+    fn early_command() -> App<'static, 'static>;
+    /// Parses the `#[stomp(early)]` arguments from the matches of
+    /// `early_command`, falling back to the environment like `parse_with`.
+    fn parse_early(matches: &ArgMatches, ctx: &Context) -> clap::Result<Self::Early>;
+    #[doc(hidden)]
+    fn spec() -> CommandSpec;
+    fn parse_with(matches: &ArgMatches, ctx: &Context) -> clap::Result<Self>;
+    /// Records the source of every field without parsing them, for
+    /// `--explain-config`.
+    #[doc(hidden)]
+    fn explain_with(matches: &ArgMatches, ctx: &Context);
+    /// Picks out the source of each field from those recorded while parsing.
+    fn sources(sources: &Sources) -> Self::Sources;
+
+    fn parse(matches: &ArgMatches) -> Self {
+        Self::parse_with(matches, &Context::new()).unwrap_or_else(|e| e.exit())
+    }
 }
 
-pub trait StompCommands {
+pub trait StompCommands: Sized {
     fn commands() -> Vec<App<'static, 'static>>;
-    fn parse(name: &str, matches: &ArgMatches) -> Self;
+    /// The arguments of the named subcommand, used to merge a default
+    /// subcommand's arguments into its parent.
+    fn args(name: &str) -> Vec<Arg<'static, 'static>>;
+    /// The argument groups of the named subcommand, merged along with its
+    /// arguments.
+    fn groups(name: &str) -> Vec<ArgGroup<'static>>;
+    #[doc(hidden)]
+    fn specs() -> Vec<SubcommandSpec>;
+    fn parse_with(name: &str, matches: &ArgMatches, ctx: &Context) -> clap::Result<Self>;
+    #[doc(hidden)]
+    fn explain_with(name: &str, matches: &ArgMatches, ctx: &Context);
+
+    fn parse(name: &str, matches: &ArgMatches) -> Self {
+        Self::parse_with(name, matches, &Context::new()).unwrap_or_else(|e| e.exit())
+    }
+}
+
+/// A set of mutually exclusive flags or options, derived from an enum with
+/// `#[derive(StompMode)]` and used in a command as a `#[stomp(mode)]` field.
+///
+/// Each unit variant becomes a flag and each single field variant becomes an
+/// option taking a value. In a command with a `#[stomp(sequence)]` field, as
+/// in `find -name x -o -type f`, any of the command's long names can also be
+/// given with a single dash, taking precedence over a short option with an
+/// attached value.
+pub trait StompMode: Sized {
+    fn args() -> Vec<Arg<'static, 'static>>;
+    /// The names of the arguments, to put them in a single group.
+    fn names() -> Vec<&'static str>;
+    fn parse(matches: &ArgMatches) -> Option<Self>;
+    /// The variant marked `#[stomp(default)]`, used when none was given.
+    fn default_mode() -> Option<Self>;
+    /// The arguments allowing each to be given any number of times, for a
+    /// `#[stomp(sequence)]` field.
+    #[doc(hidden)]
+    fn repeated_args() -> Vec<Arg<'static, 'static>>;
+    #[doc(hidden)]
+    fn specs() -> Vec<ArgSpec>;
+    /// The variant for a single occurrence of the named argument.
+    #[doc(hidden)]
+    fn parse_occurrence(name: &str, value: Option<&str>) -> Option<Self>;
+}
+
+/// Implemented by `#[derive(StompMode)]` for modes with a `#[stomp(default)]`
+/// variant, which a command's `impl_default` needs for any mode that isn't
+/// optional.
+#[doc(hidden)]
+pub trait DefaultMode: StompMode {}
+
+/// A parsed command that can be executed against some shared context.
+///
+/// `#[derive(StompCommands)]` can implement this for an enum of subcommands
+/// by adding `#[stomp(run(context = "Ctx", error = "Error"))]`, each variant
+/// is then run through its own `Run` implementation. The context defaults to
+/// `()` and the error to `Box<Error + Send + Sync>`. `before = "path"` and
+/// `after = "path"` name hooks called around every variant, with signatures
+/// `fn(&mut Ctx) -> Result<(), Error>` and
+/// `fn(&mut Ctx, Result<(), Error>) -> Result<(), Error>` respectively.
+pub trait Run<Ctx> {
+    type Err;
+    fn run(self, ctx: &mut Ctx) -> Result<(), Self::Err>;
 }
 
-pub trait ParseApp {
+pub trait ParseApp: Sized {
+    type Sources;
+    type Early;
     fn parse() -> Self;
+    fn try_parse_from_with<I, T>(args: I, ctx: &Context) -> clap::Result<Self>
+        where I: IntoIterator<Item=T>, T: Into<OsString> + Clone;
+
+    fn try_parse_from<I, T>(args: I) -> clap::Result<Self>
+        where I: IntoIterator<Item=T>, T: Into<OsString> + Clone
+    {
+        Self::try_parse_from_with(args, &Context::new())
+    }
+
+    /// Parses the process arguments along with where each field's value came
+    /// from.
+    fn parse_with_sources() -> (Self, Self::Sources);
+
+    /// Parses the given arguments along with where each field's value came
+    /// from.
+    fn try_parse_from_with_sources<I, T>(args: I, ctx: &Context) -> clap::Result<(Self, Self::Sources)>
+        where I: IntoIterator<Item=T>, T: Into<OsString> + Clone;
+
+    /// Parses the given arguments, looking up environment variables in `env`
+    /// instead of the process environment.
+    fn try_parse_from_with_env<I, T>(args: I, env: HashMap<String, String>) -> clap::Result<Self>
+        where I: IntoIterator<Item=T>, T: Into<OsString> + Clone
+    {
+        Self::try_parse_from_with(args, &Context::with_env(env))
+    }
+
+    /// Parses only the `#[stomp(early)]` arguments, ignoring everything else.
+    ///
+    /// This allows things like a `--config` argument to be used to create the
+    /// `Context` for the full parse, e.g. adding `Defaults` from a config file.
+    fn try_parse_early_from<I, T>(args: I) -> clap::Result<Self::Early>
+        where I: IntoIterator<Item=T>, T: Into<OsString> + Clone
+    {
+        Self::try_parse_early_from_with(args, &Context::new())
+    }
+
+    /// Parses only the `#[stomp(early)]` arguments, with fallbacks from the
+    /// given context.
+    fn try_parse_early_from_with<I, T>(args: I, ctx: &Context) -> clap::Result<Self::Early>
+        where I: IntoIterator<Item=T>, T: Into<OsString> + Clone;
 }
 
 impl<C> ParseApp for C where C: StompCommand {
+    type Sources = C::Sources;
+    type Early = C::Early;
+
     fn parse() -> Self {
-        C::parse(&App::get_matches(C::command()))
+        Self::try_parse_from(env::args_os()).unwrap_or_else(|e| e.exit())
+    }
+
+    fn parse_with_sources() -> (Self, Self::Sources) {
+        Self::try_parse_from_with_sources(env::args_os(), &Context::new()).unwrap_or_else(|e| e.exit())
+    }
+
+    fn try_parse_from_with_sources<I, T>(args: I, ctx: &Context) -> clap::Result<(Self, Self::Sources)>
+        where I: IntoIterator<Item=T>, T: Into<OsString> + Clone
+    {
+        let app = Self::try_parse_from_with(args, ctx)?;
+        Ok((app, C::sources(&ctx.sources())))
+    }
+
+    fn try_parse_from_with<I, T>(args: I, ctx: &Context) -> clap::Result<Self>
+        where I: IntoIterator<Item=T>, T: Into<OsString> + Clone
+    {
+        let spec = C::spec();
+        let args = scan::single_dash_longs(&spec, args.into_iter().map(Into::into).collect());
+        let (args, unknown) = scan::split_unknown(&spec, args);
+        ctx.set_unknown(unknown);
+        ctx.set_scopes(scan::scopes(&spec, &args));
+        if scan::is_given(&spec, &args, EXPLAIN_CONFIG) {
+            // Nothing is parsed so this works when required values are
+            // missing, it's reported like --help so exiting with the error
+            // prints it
+            let ctx = ctx.explaining();
+            let matches = ctx.build(|| C::command().get_matches_from_safe(args))?;
+            C::explain_with(&matches, &ctx);
+            return Err(clap::Error {
+                message: ctx.sources().to_string(),
+                kind: ErrorKind::HelpDisplayed,
+                info: None,
+            });
+        }
+        // clap 2 only has one help text, so commands are built with the full
+        // docs when --help was asked for and just the summaries for -h
+        let long_help = scan::wants_long_help(&spec, &args);
+        let previous = LONG_HELP.with(|l| l.get());
+        LONG_HELP.with(|l| l.set(long_help));
+        let result = ctx.build(|| C::command().get_matches_from_safe(args))
+            .and_then(|matches| C::parse_with(&matches, ctx));
+        LONG_HELP.with(|l| l.set(previous));
+        result
+    }
+
+    fn try_parse_early_from_with<I, T>(args: I, ctx: &Context) -> clap::Result<Self::Early>
+        where I: IntoIterator<Item=T>, T: Into<OsString> + Clone
+    {
+        let spec = C::spec();
+        let args = scan::single_dash_longs(&spec, args.into_iter().map(Into::into).collect());
+        let args = scan::early_args(&spec, args);
+        let matches = C::early_command().get_matches_from_safe(args)?;
+        C::parse_early(&matches, ctx)
     }
 }
 
@@ -26,7 +249,467 @@ impl<C> StompCommands for Option<C> where C: StompCommands {
     fn commands() -> Vec<App<'static, 'static>> {
         C::commands()
     }
-    fn parse(name: &str, matches: &ArgMatches) -> Self {
-        Some(C::parse(name, matches))
+    fn args(name: &str) -> Vec<Arg<'static, 'static>> {
+        C::args(name)
+    }
+    fn groups(name: &str) -> Vec<ArgGroup<'static>> {
+        C::groups(name)
+    }
+    fn specs() -> Vec<SubcommandSpec> {
+        C::specs()
+    }
+    fn parse_with(name: &str, matches: &ArgMatches, ctx: &Context) -> clap::Result<Self> {
+        C::parse_with(name, matches, ctx).map(Some)
+    }
+    fn explain_with(name: &str, matches: &ArgMatches, ctx: &Context) {
+        C::explain_with(name, matches, ctx)
+    }
+}
+
+impl<Ctx, C> Run<Ctx> for Option<C> where C: Run<Ctx> {
+    type Err = C::Err;
+    fn run(self, ctx: &mut Ctx) -> Result<(), Self::Err> {
+        match self {
+            Some(command) => command.run(ctx),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Runs chained subcommands in order, stopping at the first failure.
+impl<Ctx, C> Run<Ctx> for Vec<C> where C: Run<Ctx> {
+    type Err = C::Err;
+    fn run(self, ctx: &mut Ctx) -> Result<(), Self::Err> {
+        for command in self {
+            command.run(ctx)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prepares subcommands to be chained one after another, each one captures
+/// all following arguments so they can be reparsed as the next subcommand.
+///
+/// Because of this the chained subcommands must not take trailing positional
+/// arguments of their own.
+#[doc(hidden)]
+pub fn chain(commands: Vec<App<'static, 'static>>) -> Vec<App<'static, 'static>> {
+    commands.into_iter()
+        .map(|command| {
+            command
+                .setting(AppSettings::TrailingVarArg)
+                .arg(Arg::with_name(CHAIN).multiple(true).hidden(true))
+        })
+        .collect()
+}
+
+/// Panics if any of the subcommands to be chained takes positional
+/// arguments, as they would take the names of the following subcommands.
+#[doc(hidden)]
+pub fn check_chain<C>() where C: StompCommands {
+    for subcommand in C::specs() {
+        if (subcommand.spec)().has_positionals() {
+            panic!("chained subcommand '{}' can't take positional arguments", subcommand.name);
+        }
+    }
+}
+
+/// The spec of a `#[stomp(subcommands)]` variant, which only has the
+/// subcommands of the group.
+#[doc(hidden)]
+pub fn group_spec<C>() -> CommandSpec where C: StompCommands {
+    CommandSpec {
+        args: Vec::new(),
+        aliases: Vec::new(),
+        subcommands: C::specs(),
+        chain: false,
+        collects_unknown: false,
+        single_dash_longs: false,
+    }
+}
+
+/// The spec of the named subcommand, for merging a default subcommand's
+/// arguments into its parent.
+#[doc(hidden)]
+pub fn subcommand_spec<C>(name: &str) -> CommandSpec where C: StompCommands {
+    C::specs().into_iter()
+        .find(|subcommand| subcommand.name == name)
+        .map(|subcommand| (subcommand.spec)())
+        .unwrap_or_else(|| panic!("no subcommand named '{}'", name))
+}
+
+/// Panics if an argument merged in from the default subcommand `name` has
+/// the same name, long, short or positional index as one of its parent's own,
+/// or if both have a `#[stomp(unknown)]` field as they'd get the same options.
+#[doc(hidden)]
+pub fn check_default_args<C>(parent: &CommandSpec, name: &str, collects_unknown: bool) where C: StompCommands {
+    let default = subcommand_spec::<C>(name);
+    if collects_unknown && default.collects_unknown {
+        panic!("default subcommand '{}' and its parent can't both have an unknown field", name);
+    }
+    let merged = default.args;
+    let own = &parent.args[..parent.args.len() - merged.len()];
+    for arg in &merged {
+        let clash = own.iter().find(|other| {
+            other.name == arg.name
+                || (other.long.is_some() && other.long == arg.long)
+                || (other.short.is_some() && other.short == arg.short)
+                || (other.index.is_some() && other.index == arg.index)
+        });
+        if let Some(other) = clash {
+            panic!("argument '{}' of default subcommand '{}' clashes with '{}' of its parent", arg.name, name, other.name);
+        }
+    }
+}
+
+fn chain_rest(matches: &ArgMatches) -> Option<Vec<OsString>> {
+    matches.values_of_os(CHAIN).map(|values| values.map(|v| v.to_owned()).collect())
+}
+
+/// Parses every chained subcommand from a command built with `chain`, in the
+/// order they were given.
+#[doc(hidden)]
+pub fn parse_chain<C>(name: &str, matches: &ArgMatches, ctx: &Context) -> clap::Result<Vec<C>> where C: StompCommands {
+    let mut steps = Vec::new();
+    let mut rest = None;
+
+    if let (command, Some(matches)) = matches.subcommand() {
+        steps.push(C::parse_with(command, matches, &ctx.subcommand(command))?);
+        rest = chain_rest(matches);
+    }
+
+    while let Some(args) = rest.take() {
+        // Each following subcommand is parsed as a command of its own so
+        // errors show its usage, anything else gets clap's error listing
+        // the subcommands
+        let step = args.first().and_then(|arg| arg.to_str()).and_then(|arg| {
+            C::specs().iter().position(|spec| spec.is_named(arg))
+        });
+        let step = match step {
+            Some(index) => index,
+            None => {
+                let first = args.first().map(|arg| arg.to_string_lossy().into_owned()).unwrap_or_default();
+                ctx.build(|| {
+                    App::new(name.to_owned())
+                        .setting(AppSettings::NoBinaryName)
+                        .setting(AppSettings::SubcommandRequired)
+                        .subcommands(chain(C::commands()))
+                        .get_matches_from_safe(args)
+                })?;
+                // clap found a subcommand we don't know of
+                return Err(clap::Error::with_description(
+                    &format!("'{}' isn't a subcommand of '{}'", first, name),
+                    ErrorKind::UnrecognizedSubcommand));
+            }
+        };
+        let command = C::specs()[step].name;
+        let matches = ctx.build(|| {
+            chain(C::commands()).remove(step)
+                .bin_name(format!("{} {}", name, command))
+                .get_matches_from_safe(args)
+        })?;
+        steps.push(C::parse_with(command, &matches, &ctx.subcommand(command))?);
+        rest = chain_rest(&matches);
+    }
+
+    Ok(steps)
+}
+
+/// Parses every occurrence of the mode's arguments in the order they were
+/// given, for a `#[stomp(sequence)]` field.
+///
+/// The order comes from the raw arguments of the command, so this fails when
+/// they aren't known because the command wasn't parsed through `ParseApp`.
+#[doc(hidden)]
+pub fn parse_sequence<T>(specs: &[ArgSpec], ctx: &Context, usage: &str) -> clap::Result<Vec<T>> where T: StompMode {
+    let args = match ctx.args() {
+        Some(args) => args,
+        None => {
+            return Err(clap::Error::with_description(
+                "sequence fields need the raw arguments, parse the command with ParseApp",
+                ErrorKind::MissingArgumentOrSubcommand));
+        }
+    };
+    let occurrences = scan::occurrences(specs, &args).map_err(|e| {
+        clap::Error::with_description(&format!("{}\n\n{}\n\nFor more information try --help", e, usage), ErrorKind::UnknownArgument)
+    })?;
+    let names = T::names();
+    Ok(occurrences.into_iter()
+        .filter(|&(name, _)| names.contains(&name))
+        .filter_map(|(name, value)| T::parse_occurrence(name, value.as_ref().map(|v| &v[..])))
+        .collect())
+}
+
+/// Whether the command being built should have the full docs in its help.
+#[doc(hidden)]
+pub fn long_help() -> bool {
+    LONG_HELP.with(|l| l.get())
+}
+
+/// The hidden `--explain-config` flag added by `#[stomp(explain_config)]`,
+/// which prints every field with its value and source instead of running.
+#[doc(hidden)]
+pub fn explain_config() -> Arg<'static, 'static> {
+    Arg::with_name(EXPLAIN_CONFIG).long(EXPLAIN_CONFIG).hidden(true)
+}
+
+/// Warns on stderr that a `#[stomp(deprecated = "...")]` argument was used,
+/// only the first time it's seen.
+#[doc(hidden)]
+pub fn deprecated(flag: &'static str, message: &'static str) {
+    if WARNED.with(|warned| warned.borrow_mut().insert(flag)) {
+        let _ = writeln!(io::stderr(), "warning: the argument '{}' is deprecated, {}", flag, message);
+    }
+}
+
+/// Warns like `deprecated` if the argument was given by the
+/// `#[stomp(deprecated_alias = "...")]` `flag`, which only the raw arguments
+/// can tell.
+#[doc(hidden)]
+pub fn deprecated_alias(specs: &[ArgSpec], ctx: &Context, flag: &'static str, message: &'static str) {
+    if ctx.args().map_or(false, |args| scan::given_as(specs, &args, &flag[2..])) {
+        deprecated(flag, message);
+    }
+}
+
+/// The error for a `#[stomp(validate = "...")]` hook that failed.
+#[doc(hidden)]
+pub fn invalid(message: &str, usage: &str) -> clap::Error {
+    clap::Error::with_description(
+        &format!("{}\n\n{}\n\nFor more information try --help", message, usage),
+        ErrorKind::ValueValidation)
+}
+
+/// Whether the value matches a `#[stomp(pattern = "...")]`, which was
+/// already checked to be valid by the derive.
+#[doc(hidden)]
+pub fn is_match(pattern: &str, value: &str) -> bool {
+    regex::Regex::new(pattern).map(|re| re.is_match(value)).unwrap_or(false)
+}
+
+/// Calls `f` with `shorts` reserved for the command it builds, e.g. the help
+/// short set by the `StompCommands` variant containing it.
+#[doc(hidden)]
+pub fn reserve_shorts<F, R>(shorts: &[char], f: F) -> R where F: FnOnce() -> R {
+    let previous = RESERVED_SHORTS.with(|r| mem::replace(&mut *r.borrow_mut(), shorts.to_vec()));
+    let result = f();
+    RESERVED_SHORTS.with(|r| *r.borrow_mut() = previous);
+    result
+}
+
+/// The shorts reserved by `reserve_shorts` for the command being built.
+#[doc(hidden)]
+pub fn reserved_shorts() -> Vec<char> {
+    RESERVED_SHORTS.with(|r| r.borrow().clone())
+}
+
+/// Panics if any of the command's arguments, including those of its modes and
+/// default subcommand, has the help or version short of the command or the
+/// variant containing it.
+#[doc(hidden)]
+pub fn check_reserved_shorts(spec: &CommandSpec, help: char, version: char) {
+    let reserved = reserved_shorts();
+    for arg in &spec.args {
+        if let Some(short) = arg.short {
+            if short == help || short == version || reserved.contains(&short) {
+                panic!("short '-{}' of argument '{}' collides with a help or version short", short, arg.name);
+            }
+        }
+    }
+}
+
+/// Panics if an argument of the mode field `field` has the same name or long
+/// as another argument of the command, `spec` includes the mode's own.
+#[doc(hidden)]
+pub fn check_mode_args(spec: &CommandSpec, field: &str, mode: &[ArgSpec]) {
+    for arg in mode {
+        let clashes = spec.args.iter()
+            .filter(|other| other.name == arg.name || (arg.long.is_some() && other.long == arg.long))
+            .count();
+        if clashes > 1 {
+            panic!("argument '{}' of mode '{}' clashes with another argument of the command", arg.name, field);
+        }
+    }
+}
+
+/// Gives each of the `(name, long, required)` arguments a short name from
+/// the letters of its long name, avoiding those `taken`, in order. Panics if
+/// a required one has none free.
+#[doc(hidden)]
+pub fn assign_shorts(mut taken: Vec<char>, wanted: &[(&'static str, &'static str, bool)]) -> Vec<(&'static str, char)> {
+    let mut assigned = Vec::new();
+    for &(name, long, required) in wanted {
+        let short = long.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(|c| c.to_lowercase().chain(c.to_uppercase()))
+            .find(|candidate| !taken.contains(candidate));
+        match short {
+            Some(short) => {
+                taken.push(short);
+                assigned.push((name, short));
+            }
+            None if required => panic!("no free short name for argument '{}'", name),
+            None => (),
+        }
+    }
+    assigned
+}
+
+/// The short given to the argument `name` by `assign_shorts`, if any.
+#[doc(hidden)]
+pub fn auto_short(assigned: &[(&'static str, char)], name: &str) -> Option<char> {
+    assigned.iter().find(|&&(assigned, _)| assigned == name).map(|&(_, short)| short)
+}
+
+/// Adds a short given by `assign_shorts` to an argument's usage in help.
+#[doc(hidden)]
+pub fn usage_with_short(usage: &str, short: Option<char>) -> String {
+    match short {
+        Some(short) => format!("-{}, {}", short, usage),
+        None => usage.to_owned(),
+    }
+}
+
+/// The width clap wraps help to when it isn't built to detect the terminal's.
+const TERM_WIDTH: usize = 120;
+
+/// Wraps each line of `text` at spaces to fit within `width` characters.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let mut current = String::new();
+        for word in line.split(' ') {
+            if !current.is_empty() && current.chars().count() + 1 + word.chars().count() > width {
+                lines.push(current);
+                current = String::new();
+            } else if !current.is_empty() {
+                current.push(' ');
+            }
+            current.push_str(word);
+        }
+        lines.push(current);
+    }
+    lines
+}
+
+/// Renders the arguments given a `#[stomp(help_heading = "...")]` as
+/// sections like clap's own, as entries of `(heading, usage, help)`.
+///
+/// Help is wrapped to clap's width with continuation lines under the help
+/// column, or like clap it starts on the next line when the usage leaves too
+/// little room.
+#[doc(hidden)]
+pub fn render_headings(entries: &[(&str, &str, &str)]) -> String {
+    let width = entries.iter().map(|&(_, usage, _)| usage.chars().count()).max().unwrap_or(0);
+    let next_line = 4 + width + 4 > TERM_WIDTH * 2 / 5;
+    let indent = if next_line { 8 } else { 4 + width + 4 };
+    let mut headings: Vec<&str> = Vec::new();
+    for &(heading, _, _) in entries {
+        if !headings.contains(&heading) {
+            headings.push(heading);
+        }
+    }
+
+    let mut rendered = String::new();
+    for heading in headings {
+        rendered.push_str(&heading.to_uppercase());
+        rendered.push_str(":\n");
+        for &(_, usage, help) in entries.iter().filter(|&&(h, _, _)| h == heading) {
+            let mut lines = wrap(help, TERM_WIDTH - indent).into_iter();
+            if next_line {
+                rendered.push_str(&format!("    {}\n", usage));
+            } else {
+                let first = lines.next().unwrap_or_else(String::new);
+                let line = format!("    {:width$}    {}", usage, first, width = width);
+                rendered.push_str(line.trim_right());
+                rendered.push('\n');
+            }
+            for line in lines {
+                if !line.is_empty() {
+                    rendered.push_str(&" ".repeat(indent));
+                    rendered.push_str(&line);
+                }
+                rendered.push('\n');
+            }
+        }
+        rendered.push('\n');
+    }
+    rendered
+}
+
+/// Interns a string built at runtime for use in an `App<'static, 'static>`,
+/// e.g. the rendered headings. Each distinct string is kept for the rest of
+/// the program, so building a command again doesn't use more memory.
+#[doc(hidden)]
+pub fn intern(s: String) -> &'static str {
+    INTERNED.with(|interned| {
+        let mut interned = interned.borrow_mut();
+        if let Some(&s) = interned.get(&*s) {
+            return s;
+        }
+        let s: &'static str = Box::leak(s.into_boxed_str());
+        interned.insert(s);
+        s
+    })
+}
+
+/// Builds a string for an `App<'static, 'static>` the first time the place
+/// `site` needs it in the short or long help, e.g. help including a typed
+/// default. Building the command again reuses it rather than leaking another
+/// copy and running the field's `default_fn` again.
+#[doc(hidden)]
+pub fn cached<F>(site: &'static str, build: F) -> &'static str where F: FnOnce() -> String {
+    let key = (site, long_help());
+    if let Some(s) = CACHED.with(|cached| cached.borrow().get(&key).cloned()) {
+        return s;
+    }
+    // Built without the borrow held, in case the default parses a command
+    let s = intern(build());
+    CACHED.with(|cached| cached.borrow_mut().insert(key, s));
+    s
+}
+
+/// Shows a typed default in help and `--explain-config`, which is why it has
+/// to implement `Display`.
+#[doc(hidden)]
+pub fn show_default<T>(value: &T) -> String where T: fmt::Display {
+    value.to_string()
+}
+
+/// The error for a required argument that clap left for us to check, but
+/// wasn't given anywhere.
+#[doc(hidden)]
+pub fn missing_value(name: &str, usage: &str) -> clap::Error {
+    clap::Error::with_description(
+        &format!("The following required arguments were not provided:\n    <{}>\n\n{}\n\nFor more information try --help", name, usage),
+        ErrorKind::MissingRequiredArgument)
+}
+
+/// The error for a constraint between arguments that clap left for us to
+/// check, as values from the environment or the context's layers count too.
+#[doc(hidden)]
+pub fn unsatisfied(message: &str, kind: ErrorKind, usage: &str) -> clap::Error {
+    clap::Error::with_description(
+        &format!("{}\n\n{}\n\nFor more information try --help", message, usage),
+        kind)
+}
+
+/// The error for a raw `String` argument that isn't valid unicode.
+#[doc(hidden)]
+pub fn invalid_utf8(name: &str, value: &OsStr) -> clap::Error {
+    clap::Error::with_description(
+        &format!("Invalid UTF-8 was detected in the value {:?} of argument '{}'", value, name),
+        ErrorKind::InvalidUtf8)
+}
+
+/// Fails on the unknown options split out for a default subcommand's
+/// `#[stomp(unknown)]` field, when another subcommand was given instead.
+#[doc(hidden)]
+pub fn check_no_unknown(ctx: &Context, usage: &str) -> clap::Result<()> {
+    match ctx.take_unknown().first() {
+        Some(arg) => Err(clap::Error::with_description(
+            &format!("Found argument '{}' which wasn't expected, or isn't valid in this context\n\n{}\n\nFor more information try --help", arg.to_string_lossy(), usage),
+            ErrorKind::UnknownArgument)),
+        None => Ok(()),
     }
 }
@@ -0,0 +1,528 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+
+/// What we need to know about an argument to find it in the raw arguments
+/// without going through clap.
+#[doc(hidden)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub short: Option<char>,
+    pub long: Option<&'static str>,
+    /// Other long names, including hidden and deprecated ones.
+    pub aliases: Vec<&'static str>,
+    pub takes_value: bool,
+    pub early: bool,
+    /// The position of a positional argument.
+    pub index: Option<u64>,
+}
+
+/// The arguments and subcommands of a command, so the raw arguments can be
+/// split up by the subcommand they belong to.
+#[doc(hidden)]
+pub struct CommandSpec {
+    pub args: Vec<ArgSpec>,
+    pub aliases: Vec<&'static str>,
+    pub subcommands: Vec<SubcommandSpec>,
+    /// Whether the subcommands are chained, each one ending where the next
+    /// one starts.
+    pub chain: bool,
+    /// Whether unknown options are split out for a `#[stomp(unknown)]` field.
+    pub collects_unknown: bool,
+    /// Whether long names can be given with a single dash (e.g. `-name`), for
+    /// commands with a `#[stomp(sequence)]` field.
+    pub single_dash_longs: bool,
+}
+
+#[doc(hidden)]
+pub struct SubcommandSpec {
+    pub name: &'static str,
+    pub aliases: Vec<&'static str>,
+    pub spec: fn() -> CommandSpec,
+}
+
+impl SubcommandSpec {
+    /// Whether `arg` names the subcommand, by its name, the variant's aliases
+    /// or the command's own.
+    pub fn is_named(&self, arg: &str) -> bool {
+        self.name == arg || self.aliases.contains(&arg) || (self.spec)().aliases.contains(&arg)
+    }
+}
+
+impl CommandSpec {
+    /// The subcommand named by `arg`, by its name or any alias.
+    fn subcommand(&self, arg: &str) -> Option<(&'static str, CommandSpec)> {
+        self.subcommands.iter()
+            .find(|subcommand| subcommand.is_named(arg))
+            .map(|subcommand| (subcommand.name, (subcommand.spec)()))
+    }
+
+    pub fn has_positionals(&self) -> bool {
+        self.args.iter().any(|spec| spec.short.is_none() && spec.long.is_none())
+    }
+}
+
+/// What an argument turned out to be while walking the raw arguments.
+enum Found<'a> {
+    Known(&'a ArgSpec),
+    Unknown,
+    /// One of the options clap adds itself.
+    Builtin,
+    Positional,
+    /// A subcommand, which all following arguments belong to.
+    Subcommand(&'static str),
+    /// `--` and everything after it.
+    Escape,
+}
+
+/// Finds the argument named by an option, and whether the option has its value
+/// attached (as `--long=value` or `-svalue`).
+fn find<'a>(specs: &'a [ArgSpec], arg: &str) -> (Option<&'a ArgSpec>, bool) {
+    if arg.starts_with("--") {
+        let (long, has_value) = match arg[2..].find('=') {
+            Some(i) => (&arg[2..(2 + i)], true),
+            None => (&arg[2..], false),
+        };
+        (specs.iter().find(|spec| spec.long == Some(long) || spec.aliases.contains(&long)), has_value)
+    } else if arg.starts_with('-') && arg.len() > 1 {
+        let mut chars = arg[1..].chars();
+        let short = chars.next();
+        (specs.iter().find(|spec| spec.short.is_some() && spec.short == short), chars.next().is_some())
+    } else {
+        (None, false)
+    }
+}
+
+/// The option named by a long name given with a single dash (e.g. `-name`
+/// or `-name=value`) in a command allowing them, and whether it has its
+/// value attached. This takes precedence over a short option with an
+/// attached value.
+fn find_single_dash<'a>(spec: &'a CommandSpec, arg: &str) -> Option<(&'a ArgSpec, bool)> {
+    if !spec.single_dash_longs || arg.starts_with("--") || !arg.starts_with('-') || arg.len() <= 2 {
+        return None;
+    }
+    let (long, has_value) = match arg[1..].find('=') {
+        Some(i) => (&arg[1..(1 + i)], true),
+        None => (&arg[1..], false),
+    };
+    spec.args.iter()
+        .find(|spec| spec.long == Some(long) || spec.aliases.contains(&long))
+        .map(|spec| (spec, has_value))
+}
+
+/// Whether an option is one clap adds itself.
+fn is_builtin(arg: &str) -> bool {
+    match arg {
+        "-h" | "--help" | "-V" | "--version" => true,
+        _ => false,
+    }
+}
+
+/// Works out what the first of `args` is, and how many arguments it takes up
+/// including any separate value.
+///
+/// Values of unknown options can only be told apart from positional arguments
+/// when attached (as `--long=value` or `-svalue`), or when the command
+/// collects unknown options and has no positional arguments, in which case an
+/// argument following an unknown option is taken as its value unless it's a
+/// subcommand.
+fn classify<'a>(spec: &'a CommandSpec, args: &[OsString]) -> (Found<'a>, usize) {
+    let arg = match args[0].to_str() {
+        Some(arg) => arg,
+        None => return (Found::Positional, 1),
+    };
+    if arg == "--" {
+        return (Found::Escape, args.len());
+    }
+    if !arg.starts_with('-') || arg == "-" {
+        return match spec.subcommand(arg) {
+            Some((name, _)) => (Found::Subcommand(name), 1),
+            None => (Found::Positional, 1),
+        };
+    }
+    if is_builtin(arg) {
+        return (Found::Builtin, 1);
+    }
+
+    let found = match find_single_dash(spec, arg) {
+        Some((known, has_value)) => (Some(known), has_value),
+        None => find(&spec.args, arg),
+    };
+    match found {
+        (Some(known), has_value) => {
+            let len = if known.takes_value && !has_value { 2 } else { 1 };
+            (Found::Known(known), len.min(args.len()))
+        }
+        (None, has_value) => {
+            let takes_next = !has_value && spec.collects_unknown && !spec.has_positionals() && args.get(1)
+                .map_or(false, |next| next.to_str().map_or(true, |next| !next.starts_with('-') && spec.subcommand(next).is_none()));
+            (Found::Unknown, if takes_next { 2 } else { 1 })
+        }
+    }
+}
+
+/// Walks the arguments of the command `spec`, without the binary name, calling
+/// `visit` with the path of subcommands the arguments are within, the spec of
+/// that command, what was found and the arguments it takes up.
+///
+/// `parent` is the spec of the command this is a subcommand of, a chained
+/// subcommand ends at the first of its siblings.
+fn walk<F>(spec: &CommandSpec, parent: Option<&CommandSpec>, path: &mut Vec<String>, args: &[OsString], visit: &mut F)
+    where F: FnMut(&[String], &CommandSpec, &Found, &[OsString])
+{
+    let mut i = 0;
+    while i < args.len() {
+        let (found, len) = classify(spec, &args[i..]);
+        let sibling = match (&found, parent) {
+            (&Found::Positional, Some(parent)) if parent.chain => {
+                args[i].to_str().and_then(|arg| parent.subcommand(arg))
+            }
+            _ => None,
+        };
+
+        if let Some((name, sibling)) = sibling {
+            path.pop();
+            visit(path, parent.unwrap(), &Found::Subcommand(name), &args[i..(i + 1)]);
+            path.push(name.to_owned());
+            walk(&sibling, parent, path, &args[(i + 1)..], visit);
+            return;
+        }
+
+        visit(path, spec, &found, &args[i..(i + len)]);
+        if let Found::Subcommand(name) = found {
+            let (_, subcommand) = spec.subcommand(name).unwrap();
+            path.push(name.to_owned());
+            walk(&subcommand, Some(spec), path, &args[(i + 1)..], visit);
+            path.pop();
+            return;
+        }
+        i += len;
+    }
+}
+
+/// Keeps only the early arguments of the top level command and their values,
+/// skipping the values of any other known options so they aren't mistaken for
+/// options themselves. Nothing after a subcommand or `--` is kept.
+///
+/// The first argument is the binary name and is always kept, short flags
+/// combined with an early option (e.g. `-vc file`) are not recognised.
+pub fn early_args(spec: &CommandSpec, args: Vec<OsString>) -> Vec<OsString> {
+    let mut kept: Vec<OsString> = args.iter().take(1).cloned().collect();
+    walk(spec, None, &mut Vec::new(), &args[kept.len()..], &mut |path, _, found, args| {
+        if let Found::Known(spec) = *found {
+            if path.is_empty() && spec.early {
+                kept.extend(args.iter().cloned());
+            }
+        }
+    });
+    kept
+}
+
+/// Gives an extra dash to the long names given with a single one in commands
+/// allowing them, so clap doesn't take them as short options.
+pub fn single_dash_longs(spec: &CommandSpec, args: Vec<OsString>) -> Vec<OsString> {
+    let mut rewritten: Vec<OsString> = args.iter().take(1).cloned().collect();
+    walk(spec, None, &mut Vec::new(), &args[rewritten.len()..], &mut |_, spec, found, args| {
+        if let Found::Known(_) = *found {
+            if let Some(arg) = args[0].to_str() {
+                if find_single_dash(spec, arg).is_some() {
+                    rewritten.push(OsString::from(format!("-{}", arg)));
+                    rewritten.extend(args[1..].iter().cloned());
+                    return;
+                }
+            }
+        }
+        rewritten.extend(args.iter().cloned());
+    });
+    rewritten
+}
+
+/// Splits out the options that aren't known, along with their values, for
+/// each command collecting them so the rest can be parsed by clap. The
+/// unknown options are keyed by the path of subcommands to the command.
+///
+/// Options of commands that don't collect them are left for clap to report,
+/// and everything after `--` is kept.
+pub fn split_unknown(spec: &CommandSpec, args: Vec<OsString>) -> (Vec<OsString>, HashMap<Vec<String>, Vec<OsString>>) {
+    let mut known: Vec<OsString> = args.iter().take(1).cloned().collect();
+    let mut unknown: HashMap<Vec<String>, Vec<OsString>> = HashMap::new();
+    walk(spec, None, &mut Vec::new(), &args[known.len()..], &mut |path, spec, found, args| {
+        match *found {
+            Found::Unknown if spec.collects_unknown => {
+                unknown.entry(path.to_vec()).or_insert_with(Vec::new).extend(args.iter().cloned());
+            }
+            _ => known.extend(args.iter().cloned()),
+        }
+    });
+    (known, unknown)
+}
+
+/// Splits the arguments into those of each command given, keyed by the path
+/// of subcommands to the command. Each command's arguments start with its
+/// name as given, and end where its subcommand starts.
+pub fn scopes(spec: &CommandSpec, args: &[OsString]) -> Vec<(Vec<String>, Vec<OsString>)> {
+    let mut scopes = vec![(Vec::new(), args.iter().take(1).cloned().collect::<Vec<_>>())];
+    walk(spec, None, &mut Vec::new(), &args[scopes[0].1.len()..], &mut |path, _, found, args| {
+        if let Found::Subcommand(name) = *found {
+            let mut path = path.to_vec();
+            path.push(name.to_owned());
+            scopes.push((path, args.to_vec()));
+        } else if let Some(scope) = scopes.iter_mut().rev().find(|scope| &scope.0[..] == path) {
+            scope.1.extend(args.iter().cloned());
+        }
+    });
+    scopes
+}
+
+/// Whether the top level command's option named `name` was given.
+pub fn is_given(spec: &CommandSpec, args: &[OsString], name: &str) -> bool {
+    let mut given = false;
+    walk(spec, None, &mut Vec::new(), &args[args.len().min(1)..], &mut |path, _, found, _| {
+        if let Found::Known(spec) = *found {
+            given = given || (path.is_empty() && spec.name == name);
+        }
+    });
+    given
+}
+
+/// Whether `--help` was given as an option to any of the commands, rather
+/// than as a value or after `--`.
+pub fn wants_long_help(spec: &CommandSpec, args: &[OsString]) -> bool {
+    let mut wanted = false;
+    walk(spec, None, &mut Vec::new(), &args[args.len().min(1)..], &mut |_, _, found, args| {
+        if let Found::Builtin = *found {
+            wanted = wanted || args[0].to_str() == Some("--help");
+        }
+    });
+    wanted
+}
+
+/// Every occurrence of a known option in order, along with its value for
+/// options taking one.
+///
+/// A long name given with a single dash (e.g. `-name`) is an error, as clap
+/// would take it as a short option with an attached value.
+pub fn occurrences(specs: &[ArgSpec], args: &[OsString]) -> Result<Vec<(&'static str, Option<String>)>, String> {
+    let mut args = args.iter().skip(1);
+    let mut found = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if arg.starts_with('-') && !arg.starts_with("--") && arg.len() > 2 {
+            let name = arg[1..].split('=').next().unwrap();
+            if let Some(long) = specs.iter().filter_map(|spec| spec.long).find(|&long| long == name) {
+                return Err(format!("found argument '{}', use '--{}' for long names", arg, long));
+            }
+        }
+        if let (Some(spec), has_value) = find(specs, &arg) {
+            let value = if !spec.takes_value {
+                None
+            } else if has_value {
+                let attached = if arg.starts_with("--") {
+                    &arg[(arg.find('=').unwrap() + 1)..]
+                } else {
+                    let rest = &arg[(1 + spec.short.unwrap().len_utf8())..];
+                    if rest.starts_with('=') { &rest[1..] } else { rest }
+                };
+                Some(attached.to_owned())
+            } else {
+                args.next().map(|value| value.to_string_lossy().into_owned())
+            };
+            found.push((spec.name, value));
+        }
+    }
+
+    Ok(found)
+}
+
+/// Whether one of `specs` was given by the long name `long`, rather than its
+/// other names.
+pub fn given_as(specs: &[ArgSpec], args: &[OsString], long: &str) -> bool {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if let (Some(spec), has_value) = find(specs, &arg) {
+            if arg.starts_with("--") && arg[2..].split('=').next() == Some(long) {
+                return true;
+            }
+            if spec.takes_value && !has_value {
+                args.next();
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use super::{ ArgSpec, CommandSpec, SubcommandSpec, early_args, given_as, is_given, occurrences, scopes, single_dash_longs, split_unknown, wants_long_help };
+
+    fn option(name: &'static str, short: char, takes_value: bool) -> ArgSpec {
+        ArgSpec { name: name, short: Some(short), long: Some(name), aliases: Vec::new(), takes_value: takes_value, early: false, index: None }
+    }
+
+    fn command(args: Vec<ArgSpec>, subcommands: Vec<SubcommandSpec>) -> CommandSpec {
+        CommandSpec {
+            args: args,
+            aliases: Vec::new(),
+            subcommands: subcommands,
+            chain: false,
+            collects_unknown: false,
+            single_dash_longs: false,
+        }
+    }
+
+    fn remote_add() -> CommandSpec {
+        let mut spec = command(vec![option("fetch", 'f', false)], Vec::new());
+        spec.collects_unknown = true;
+        spec
+    }
+
+    fn remote() -> CommandSpec {
+        command(vec![option("verbose", 'v', false)], vec![
+            SubcommandSpec { name: "add", aliases: Vec::new(), spec: remote_add },
+        ])
+    }
+
+    /// `git [--config <file>] [unknown...] remote [-v] add [-f] [unknown...]`
+    fn git() -> CommandSpec {
+        let mut config = option("config", 'c', true);
+        config.early = true;
+        let mut spec = command(vec![config], vec![
+            SubcommandSpec { name: "remote", aliases: vec!["r"], spec: remote },
+        ]);
+        spec.collects_unknown = true;
+        spec
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn unknown_options_are_split_per_command() {
+        let (known, unknown) = split_unknown(&git(), args(&["git", "--paginate", "-c", "x", "remote", "-v", "add", "--tags", "-f"]));
+        assert_eq!(known, args(&["git", "-c", "x", "remote", "-v", "add", "-f"]));
+        assert_eq!(unknown[&Vec::<String>::new()], args(&["--paginate"]));
+        assert_eq!(unknown[&vec!["remote".to_owned(), "add".to_owned()]], args(&["--tags"]));
+    }
+
+    #[test]
+    fn unknown_option_doesnt_take_subcommand() {
+        let (known, unknown) = split_unknown(&git(), args(&["git", "--paginate", "remote", "add", "--depth", "1"]));
+        assert_eq!(known, args(&["git", "remote", "add"]));
+        assert_eq!(unknown[&Vec::<String>::new()], args(&["--paginate"]));
+        assert_eq!(unknown[&vec!["remote".to_owned(), "add".to_owned()]], args(&["--depth", "1"]));
+    }
+
+    #[test]
+    fn unknown_options_are_left_when_not_collected() {
+        let (known, unknown) = split_unknown(&git(), args(&["git", "remote", "--bogus", "add"]));
+        assert_eq!(known, args(&["git", "remote", "--bogus", "add"]));
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn everything_after_escape_is_kept() {
+        let (known, unknown) = split_unknown(&git(), args(&["git", "--paginate", "--", "--help", "remote"]));
+        assert_eq!(known, args(&["git", "--", "--help", "remote"]));
+        assert_eq!(unknown[&Vec::<String>::new()], args(&["--paginate"]));
+    }
+
+    #[test]
+    fn early_args_stop_at_subcommand() {
+        let kept = early_args(&git(), args(&["git", "-c", "a", "r", "-c", "b", "add"]));
+        assert_eq!(kept, args(&["git", "-c", "a"]));
+        let kept = early_args(&git(), args(&["git", "--", "-c", "b"]));
+        assert_eq!(kept, args(&["git"]));
+    }
+
+    #[test]
+    fn scopes_follow_nested_subcommands() {
+        let found = scopes(&git(), &args(&["git", "-c", "remote", "remote", "-v", "add", "-f", "--", "remote"]));
+        assert_eq!(found, vec![
+            (Vec::new(), args(&["git", "-c", "remote"])),
+            (vec!["remote".to_owned()], args(&["remote", "-v"])),
+            (vec!["remote".to_owned(), "add".to_owned()], args(&["add", "-f", "--", "remote"])),
+        ]);
+    }
+
+    #[test]
+    fn chained_subcommands_end_at_the_next() {
+        let mut spec = remote();
+        spec.chain = true;
+        let found = scopes(&spec, &args(&["remote", "add", "-f", "add"]));
+        assert_eq!(found, vec![
+            (Vec::new(), args(&["remote"])),
+            (vec!["add".to_owned()], args(&["add", "-f"])),
+            (vec!["add".to_owned()], args(&["add"])),
+        ]);
+    }
+
+    #[test]
+    fn occurrences_are_in_order() {
+        let specs = vec![option("name", 'n', true), option("all", 'a', false)];
+        let found = occurrences(&specs, &args(&["find", "-a", "--name", "x", "-ny", "--name=z", "--", "-a"])).unwrap();
+        assert_eq!(found, vec![
+            ("all", None),
+            ("name", Some("x".to_owned())),
+            ("name", Some("y".to_owned())),
+            ("name", Some("z".to_owned())),
+        ]);
+    }
+
+    #[test]
+    fn single_dash_long_names_are_rejected() {
+        let specs = vec![option("name", 'n', true)];
+        assert!(occurrences(&specs, &args(&["find", "-name", "x"])).is_err());
+        assert!(occurrences(&specs, &args(&["find", "-nx"])).is_ok());
+    }
+
+    #[test]
+    fn single_dash_long_names_are_rewritten_where_allowed() {
+        let mut find = command(vec![option("name", 'n', true), option("or", 'o', false), option("type", 't', true)], Vec::new());
+        let given = args(&["find", "-name", "x", "-o", "-type=f", "-nx", "-name", "-or"]);
+        assert_eq!(single_dash_longs(&find, given.clone()), given);
+        find.single_dash_longs = true;
+        assert_eq!(single_dash_longs(&find, given.clone()), args(&["find", "--name", "x", "-o", "--type=f", "-nx", "--name", "-or"]));
+        let found = occurrences(&find.args, &single_dash_longs(&find, args(&["find", "-name", "x", "-o", "-type", "f"]))).unwrap();
+        assert_eq!(found, vec![
+            ("name", Some("x".to_owned())),
+            ("or", None),
+            ("type", Some("f".to_owned())),
+        ]);
+    }
+
+    #[test]
+    fn long_help_is_only_an_option() {
+        assert!(wants_long_help(&git(), &args(&["git", "remote", "add", "--help"])));
+        assert!(!wants_long_help(&git(), &args(&["git", "-c", "--help"])));
+        assert!(!wants_long_help(&git(), &args(&["git", "--", "--help"])));
+        assert!(!wants_long_help(&git(), &args(&["git", "-h"])));
+    }
+
+    #[test]
+    fn options_are_given_at_the_top_level() {
+        assert!(is_given(&git(), &args(&["git", "-c", "x", "remote"]), "config"));
+        assert!(!is_given(&git(), &args(&["git", "remote", "-c", "x"]), "config"));
+        assert!(!is_given(&git(), &args(&["git", "--", "-c", "x"]), "config"));
+    }
+
+    #[test]
+    fn aliases_are_found() {
+        let mut config = option("config", 'c', true);
+        config.aliases.push("conf");
+        let specs = vec![config, option("verbose", 'v', false)];
+        assert!(given_as(&specs, &args(&["git", "-v", "--conf", "x"]), "conf"));
+        assert!(given_as(&specs, &args(&["git", "--conf=x"]), "conf"));
+        assert!(!given_as(&specs, &args(&["git", "--config", "--conf"]), "conf"));
+        assert!(!given_as(&specs, &args(&["git", "--", "--conf"]), "conf"));
+        assert_eq!(occurrences(&specs, &args(&["git", "--conf", "x"])).unwrap(), vec![("config", Some("x".to_owned()))]);
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+
+/// Where the value of a field came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    CommandLine,
+    /// The named environment variable.
+    Environment(String),
+    /// One of the context's layers, with its description of where the value
+    /// was found.
+    Layer(String),
+    /// The field's `default_value` or a `StompMode`'s default variant.
+    Default,
+    /// The field wasn't given anywhere.
+    Unset,
+}
+
+/// The source of a single field.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// The subcommands the field is within.
+    pub path: Vec<String>,
+    pub field: String,
+    /// The values as given, before parsing. Flags and counters record the
+    /// number of times they were given.
+    pub values: Vec<String>,
+    pub source: Source,
+}
+
+/// The sources of every field parsed with a `Context`, in the order they were
+/// parsed.
+#[derive(Clone, Debug, Default)]
+pub struct Sources {
+    entries: Vec<Entry>,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::CommandLine => write!(f, "command line"),
+            Source::Environment(ref var) => write!(f, "environment variable {}", var),
+            Source::Layer(ref description) => write!(f, "{}", description),
+            Source::Default => write!(f, "default"),
+            Source::Unset => write!(f, "unset"),
+        }
+    }
+}
+
+impl Entry {
+    /// The full dotted key, including any subcommands.
+    pub fn key(&self) -> String {
+        let mut key = self.path.join(".");
+        if !key.is_empty() {
+            key.push('.');
+        }
+        key.push_str(&self.field);
+        key
+    }
+}
+
+impl Sources {
+    pub fn new() -> Sources {
+        Sources { entries: Vec::new() }
+    }
+
+    /// Finds the source of the field `field` within the subcommands named by
+    /// `path`.
+    pub fn get(&self, path: &[&str], field: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.field == field && entry.path.iter().eq(path.iter()))
+    }
+
+    /// The sources of every field within subcommands of the command named
+    /// by `path`.
+    pub fn nested(&self, path: &[&str]) -> Sources {
+        Sources {
+            entries: self.entries.iter()
+                .filter(|entry| entry.path.len() > path.len() && entry.path.iter().zip(path).all(|(a, b)| a == b))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn iter(&self) -> ::std::slice::Iter<Entry> {
+        self.entries.iter()
+    }
+
+    /// Records a field, replacing any earlier parse of the same field.
+    #[doc(hidden)]
+    pub fn record(&mut self, entry: Entry) {
+        match self.entries.iter().position(|e| e.path == entry.path && e.field == entry.field) {
+            Some(i) => self.entries[i] = entry,
+            None => self.entries.push(entry),
+        }
+    }
+}
+
+/// One line per field, as printed by `--explain-config`.
+impl fmt::Display for Sources {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.entries {
+            write!(f, "{} = ", entry.key())?;
+            if entry.values.is_empty() {
+                write!(f, "<none>")?;
+            } else {
+                write!(f, "{}", entry.values.join(", "))?;
+            }
+            writeln!(f, " ({})", entry.source)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+//! Value types for arguments naming files, checked when the arguments are
+//! validated so errors are reported with the path along with any other usage
+//! errors.
+//!
+//! Fields of these types get a default `value_name` of `FILE`, `DIR`,
+//! `INPUT` or `OUTPUT`, which shell completions use as a hint.
+
+use std::fmt;
+use std::fs::{ self, File };
+use std::io::{ self, Read, Write };
+use std::ops::Deref;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+
+/// A path to a file that exists.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExistingFile(PathBuf);
+
+/// A path to a directory that exists.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExistingDir(PathBuf);
+
+/// A path that doesn't exist yet, in a directory that does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NewFile(PathBuf);
+
+/// A file to read, or stdin when given as `-`.
+///
+/// The path is checked when the argument is validated, and the file is opened
+/// on the first read.
+pub enum Input {
+    Stdin(io::Stdin),
+    File(PathBuf, Option<File>),
+}
+
+/// A file to write, or stdout when given as `-`.
+///
+/// The path is checked when the argument is validated, and the file is only
+/// created, truncating any existing file, on the first write. A failed parse
+/// leaves an existing file untouched.
+pub enum Output {
+    Stdout(io::Stdout),
+    File(PathBuf, Option<File>),
+}
+
+impl FromStr for ExistingFile {
+    type Err = String;
+    fn from_str(s: &str) -> Result<ExistingFile, String> {
+        let path = PathBuf::from(s);
+        match fs::metadata(&path) {
+            Ok(ref metadata) if metadata.is_file() => Ok(ExistingFile(path)),
+            Ok(_) => Err(format!("'{}' is not a file", path.display())),
+            Err(e) => Err(format!("'{}': {}", path.display(), e)),
+        }
+    }
+}
+
+impl FromStr for ExistingDir {
+    type Err = String;
+    fn from_str(s: &str) -> Result<ExistingDir, String> {
+        let path = PathBuf::from(s);
+        match fs::metadata(&path) {
+            Ok(ref metadata) if metadata.is_dir() => Ok(ExistingDir(path)),
+            Ok(_) => Err(format!("'{}' is not a directory", path.display())),
+            Err(e) => Err(format!("'{}': {}", path.display(), e)),
+        }
+    }
+}
+
+impl FromStr for NewFile {
+    type Err = String;
+    fn from_str(s: &str) -> Result<NewFile, String> {
+        let path = PathBuf::from(s);
+        if fs::metadata(&path).is_ok() {
+            return Err(format!("'{}' already exists", path.display()));
+        }
+        // A bare file name is in the current directory
+        let parent = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        match fs::metadata(&parent) {
+            Ok(ref metadata) if metadata.is_dir() => Ok(NewFile(path)),
+            Ok(_) => Err(format!("'{}' is not a directory", parent.display())),
+            Err(e) => Err(format!("'{}': {}", parent.display(), e)),
+        }
+    }
+}
+
+impl FromStr for Input {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Input, String> {
+        if s == "-" {
+            return Ok(Input::Stdin(io::stdin()));
+        }
+        let path = PathBuf::from(s);
+        match fs::metadata(&path) {
+            Ok(ref metadata) if metadata.is_dir() => Err(format!("'{}' is a directory", path.display())),
+            Ok(_) => Ok(Input::File(path, None)),
+            Err(e) => Err(format!("'{}': {}", path.display(), e)),
+        }
+    }
+}
+
+impl FromStr for Output {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Output, String> {
+        if s == "-" {
+            return Ok(Output::Stdout(io::stdout()));
+        }
+        let path = PathBuf::from(s);
+        match fs::metadata(&path) {
+            Ok(ref metadata) if metadata.is_dir() => {
+                return Err(format!("'{}' is a directory", path.display()));
+            }
+            Ok(ref metadata) if metadata.permissions().readonly() => {
+                return Err(format!("'{}' is read only", path.display()));
+            }
+            Ok(_) => return Ok(Output::File(path, None)),
+            Err(_) => (),
+        }
+        let parent = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        match fs::metadata(&parent) {
+            Ok(ref metadata) if metadata.is_dir() => Ok(Output::File(path, None)),
+            Ok(_) => Err(format!("'{}' is not a directory", parent.display())),
+            Err(e) => Err(format!("'{}': {}", parent.display(), e)),
+        }
+    }
+}
+
+macro_rules! path_type {
+    ($ty:ident) => {
+        impl $ty {
+            pub fn into_path_buf(self) -> PathBuf {
+                self.0
+            }
+        }
+
+        impl Deref for $ty {
+            type Target = Path;
+            fn deref(&self) -> &Path {
+                &self.0
+            }
+        }
+
+        impl AsRef<Path> for $ty {
+            fn as_ref(&self) -> &Path {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.display().fmt(f)
+            }
+        }
+    }
+}
+
+path_type!(ExistingFile);
+path_type!(ExistingDir);
+path_type!(NewFile);
+
+impl Input {
+    /// The path of the file, `None` for stdin.
+    pub fn path(&self) -> Option<&Path> {
+        match *self {
+            Input::Stdin(_) => None,
+            Input::File(ref path, _) => Some(path),
+        }
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Input::Stdin(ref mut stdin) => stdin.read(buf),
+            Input::File(ref path, ref mut file) => {
+                if file.is_none() {
+                    *file = Some(File::open(path)?);
+                }
+                file.as_mut().unwrap().read(buf)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Input::Stdin(_) => write!(f, "-"),
+            Input::File(ref path, _) => path.display().fmt(f),
+        }
+    }
+}
+
+impl Output {
+    /// The path of the file, `None` for stdout.
+    pub fn path(&self) -> Option<&Path> {
+        match *self {
+            Output::Stdout(_) => None,
+            Output::File(ref path, _) => Some(path),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Output::Stdout(ref mut stdout) => stdout.write(buf),
+            Output::File(ref path, ref mut file) => {
+                if file.is_none() {
+                    *file = Some(File::create(path)?);
+                }
+                file.as_mut().unwrap().write(buf)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Output::Stdout(ref mut stdout) => stdout.flush(),
+            Output::File(_, Some(ref mut file)) => file.flush(),
+            Output::File(_, None) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Output::Stdout(_) => write!(f, "-"),
+            Output::File(ref path, _) => path.display().fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{ self, File };
+    use std::io::{ Read, Write };
+    use std::path::PathBuf;
+    use super::{ Input, Output };
+
+    /// A file in the temporary directory holding `contents`.
+    fn existing(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("stomp-types-{}", name));
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    fn contents(path: &PathBuf) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn parsing_output_keeps_existing_file() {
+        let path = existing("output-kept", "keep");
+        let output = path.to_str().unwrap().parse::<Output>().unwrap();
+        assert_eq!(contents(&path), "keep");
+        drop(output);
+        assert_eq!(contents(&path), "keep");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_parse_keeps_existing_file() {
+        let path = existing("output-failed", "keep");
+        // The value is validated, then another argument fails the parse
+        let parsed = path.to_str().unwrap().parse::<Output>()
+            .and_then(|output| "x/missing/out".parse::<Output>().map(|_| output));
+        assert!(parsed.is_err());
+        assert_eq!(contents(&path), "keep");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn output_is_created_on_first_write() {
+        let path = existing("output-written", "old");
+        let mut output = path.to_str().unwrap().parse::<Output>().unwrap();
+        output.write_all(b"new").unwrap();
+        output.flush().unwrap();
+        assert_eq!(contents(&path), "new");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn output_rejects_missing_directory() {
+        assert!("stomp-missing-dir/out".parse::<Output>().is_err());
+        assert!(env::temp_dir().to_str().unwrap().parse::<Output>().is_err());
+    }
+
+    #[test]
+    fn input_is_read_lazily() {
+        let path = existing("input", "data");
+        let mut input = path.to_str().unwrap().parse::<Input>().unwrap();
+        let mut read = String::new();
+        input.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "data");
+        assert_eq!(input.to_string(), path.display().to_string());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn input_rejects_missing_file() {
+        assert!("stomp-missing-input".parse::<Input>().is_err());
+        assert_eq!("-".parse::<Input>().unwrap().to_string(), "-");
+    }
+}
@@ -0,0 +1,425 @@
+//! Value types for common human friendly formats.
+//!
+//! Each displays in a canonical form that parses back to the same value, so
+//! they can be used with `#[stomp(default = "...")]` and shown in help.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time;
+
+/// A duration made of whole numbers of units, like `1h30m` or `250ms`.
+///
+/// The units are `d`, `h`, `m`, `s`, `ms`, `us` and `ns`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Duration(pub time::Duration);
+
+/// A number of bytes, like `512`, `10MiB` or `1.5G`.
+///
+/// `KB`, `MB`, `GB`, `TB` and `PB` are powers of 1000, while `KiB`, `MiB`,
+/// `GiB`, `TiB` and `PiB` and the bare `K`, `M`, `G`, `T` and `P` are powers of
+/// 1024. Units are case insensitive and may be followed by `B`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub u64);
+
+/// A set of integers given as a list of values and inclusive ranges, like
+/// `0-3,7`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RangeList {
+    /// Sorted, non-overlapping and non-adjacent inclusive ranges.
+    ranges: Vec<(u64, u64)>,
+}
+
+/// A percentage like `50%`, the `%` is optional.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Percentage(f64);
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+const DURATION_UNITS: &'static [(&'static str, u64)] = &[
+    ("d", 86_400 * NANOS_PER_SEC),
+    ("h", 3_600 * NANOS_PER_SEC),
+    ("m", 60 * NANOS_PER_SEC),
+    ("s", NANOS_PER_SEC),
+    ("ms", 1_000_000),
+    ("us", 1_000),
+    ("ns", 1),
+];
+
+const BINARY_UNITS: &'static [&'static str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+/// Splits the leading digits (and `.` if allowed) off `s`.
+fn split_number(s: &str, decimal: bool) -> (&str, &str) {
+    let end = s.find(|c: char| !(c.is_digit(10) || (decimal && c == '.'))).unwrap_or(s.len());
+    (&s[..end], &s[end..])
+}
+
+impl FromStr for Duration {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Duration, String> {
+        if s.is_empty() {
+            return Err("empty duration".to_owned());
+        }
+        // Seconds and nanoseconds are kept apart so the whole range of
+        // `time::Duration` can be given, as anything can be displayed
+        let mut rest = s;
+        let mut secs: u64 = 0;
+        let mut nanos: u64 = 0;
+        while !rest.is_empty() {
+            let (number, after) = split_number(rest, false);
+            if number.is_empty() {
+                return Err(format!("expected a number in duration {:?}", s));
+            }
+            let (unit, after) = split_at_digit(after);
+            let &(_, unit_nanos) = DURATION_UNITS.iter()
+                .find(|&&(name, _)| name == unit)
+                .ok_or_else(|| if unit.is_empty() {
+                    format!("missing unit in duration {:?}", s)
+                } else {
+                    format!("unknown unit {:?} in duration {:?}", unit, s)
+                })?;
+            let too_long = || format!("duration {:?} is too long", s);
+            let number = number.parse::<u64>().map_err(|_| too_long())?;
+            let (whole, part) = if unit_nanos >= NANOS_PER_SEC {
+                (number.checked_mul(unit_nanos / NANOS_PER_SEC).ok_or_else(&too_long)?, 0)
+            } else {
+                let per_sec = NANOS_PER_SEC / unit_nanos;
+                (number / per_sec, number % per_sec * unit_nanos)
+            };
+            nanos += part;
+            secs = secs.checked_add(whole)
+                .and_then(|secs| secs.checked_add(nanos / NANOS_PER_SEC))
+                .ok_or_else(&too_long)?;
+            nanos %= NANOS_PER_SEC;
+            rest = after;
+        }
+        Ok(Duration(time::Duration::new(secs, nanos as u32)))
+    }
+}
+
+/// Splits the leading non-digits off `s`.
+fn split_at_digit(s: &str) -> (&str, &str) {
+    let end = s.find(|c: char| c.is_digit(10)).unwrap_or(s.len());
+    (&s[..end], &s[end..])
+}
+
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0.as_secs();
+        let nanos = self.0.subsec_nanos() as u64;
+        if secs == 0 && nanos == 0 {
+            return write!(f, "0s");
+        }
+        let parts = [
+            (secs / 86_400, "d"),
+            (secs % 86_400 / 3_600, "h"),
+            (secs % 3_600 / 60, "m"),
+            (secs % 60, "s"),
+            (nanos / 1_000_000, "ms"),
+            (nanos % 1_000_000 / 1_000, "us"),
+            (nanos % 1_000, "ns"),
+        ];
+        for &(value, unit) in &parts {
+            if value > 0 {
+                write!(f, "{}{}", value, unit)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<time::Duration> for Duration {
+    fn from(duration: time::Duration) -> Duration {
+        Duration(duration)
+    }
+}
+
+impl From<Duration> for time::Duration {
+    fn from(duration: Duration) -> time::Duration {
+        duration.0
+    }
+}
+
+fn byte_unit(unit: &str) -> Option<u64> {
+    let unit = unit.to_lowercase();
+    let (prefix, base) = if unit.ends_with("ib") {
+        (&unit[..(unit.len() - 2)], 1024)
+    } else if unit.len() == 2 && unit.ends_with('b') {
+        (&unit[..1], 1000)
+    } else {
+        (&unit[..], 1024)
+    };
+    let power = match prefix {
+        "" | "b" => 0,
+        "k" => 1,
+        "m" => 2,
+        "g" => 3,
+        "t" => 4,
+        "p" => 5,
+        _ => return None,
+    };
+    // "ib" alone isn't a unit
+    if power == 0 && unit != "" && unit != "b" {
+        return None;
+    }
+    Some((0..power).fold(1, |value, _| value * base))
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<ByteSize, String> {
+        let (number, unit) = split_number(s, true);
+        let multiplier = byte_unit(unit.trim())
+            .ok_or_else(|| format!("unknown unit {:?} in size {:?}", unit, s))?;
+        let mut parts = number.splitn(2, '.');
+        let whole = parts.next().unwrap_or("");
+        let fraction = parts.next().unwrap_or("");
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(format!("expected a number in size {:?}", s));
+        }
+        let too_large = || format!("size {:?} is too large", s);
+
+        let whole = if whole.is_empty() { 0 } else { whole.parse::<u64>().map_err(|_| too_large())? };
+        let mut bytes = whole.checked_mul(multiplier).ok_or_else(&too_large)?;
+        if !fraction.is_empty() {
+            if fraction.len() > 18 {
+                return Err(format!("too many decimal places in size {:?}", s));
+            }
+            let scale = (0..fraction.len()).fold(1u64, |scale, _| scale * 10);
+            let fraction = fraction.parse::<u64>()
+                .ok()
+                .and_then(|fraction| fraction.checked_mul(multiplier))
+                .ok_or_else(&too_large)?;
+            if fraction % scale != 0 {
+                return Err(format!("size {:?} is not a whole number of bytes", s));
+            }
+            bytes = bytes.checked_add(fraction / scale).ok_or_else(&too_large)?;
+        }
+        Ok(ByteSize(bytes))
+    }
+}
+
+/// Uses the largest binary unit the size is a whole number of.
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut value = self.0;
+        let mut unit = 0;
+        while value != 0 && value % 1024 == 0 && unit + 1 < BINARY_UNITS.len() {
+            value /= 1024;
+            unit += 1;
+        }
+        write!(f, "{}{}", value, BINARY_UNITS[unit])
+    }
+}
+
+impl RangeList {
+    pub fn contains(&self, value: u64) -> bool {
+        self.ranges.iter().any(|&(start, end)| start <= value && value <= end)
+    }
+
+    /// Every value in the list in ascending order.
+    pub fn values(&self) -> Vec<u64> {
+        self.ranges.iter().flat_map(|&(start, end)| (start..end).chain(Some(end))).collect()
+    }
+
+    /// The inclusive ranges in ascending order.
+    pub fn ranges(&self) -> &[(u64, u64)] {
+        &self.ranges
+    }
+}
+
+impl FromStr for RangeList {
+    type Err = String;
+    fn from_str(s: &str) -> Result<RangeList, String> {
+        let mut ranges = Vec::new();
+        for part in s.split(',') {
+            let part = part.trim();
+            let parse = |value: &str| {
+                value.trim().parse::<u64>().map_err(|e| format!("invalid value {:?} in list {:?}: {}", value, s, e))
+            };
+            let range = match part.find('-') {
+                Some(i) => (parse(&part[..i])?, parse(&part[(i + 1)..])?),
+                None => {
+                    let value = parse(part)?;
+                    (value, value)
+                }
+            };
+            if range.0 > range.1 {
+                return Err(format!("range {:?} in list {:?} is backwards", part, s));
+            }
+            ranges.push(range);
+        }
+
+        ranges.sort();
+        let mut merged: Vec<(u64, u64)> = Vec::new();
+        for (start, end) in ranges {
+            if let Some(last) = merged.last_mut() {
+                if start <= last.1.saturating_add(1) {
+                    if end > last.1 {
+                        last.1 = end;
+                    }
+                    continue;
+                }
+            }
+            merged.push((start, end));
+        }
+        Ok(RangeList { ranges: merged })
+    }
+}
+
+impl fmt::Display for RangeList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &(start, end)) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Percentage {
+    /// The percentage, e.g. `50.0` for `50%`.
+    pub fn percent(&self) -> f64 {
+        self.0
+    }
+
+    /// The percentage as a ratio, e.g. `0.5` for `50%`.
+    pub fn ratio(&self) -> f64 {
+        self.0 / 100.0
+    }
+}
+
+impl FromStr for Percentage {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Percentage, String> {
+        let number = if s.ends_with('%') { &s[..(s.len() - 1)] } else { s };
+        let percent = number.trim().parse::<f64>()
+            .map_err(|e| format!("invalid percentage {:?}: {}", s, e))?;
+        if !percent.is_finite() || percent < 0.0 {
+            return Err(format!("invalid percentage {:?}", s));
+        }
+        Ok(Percentage(percent))
+    }
+}
+
+impl fmt::Display for Percentage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}%", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::{ ByteSize, Duration, Percentage, RangeList };
+
+    fn round_trip<T>(s: &str) -> String where T: ::std::str::FromStr + ::std::fmt::Display, T::Err: ::std::fmt::Debug {
+        let value: T = s.parse().unwrap();
+        let shown = value.to_string();
+        assert_eq!(shown.parse::<T>().unwrap().to_string(), shown);
+        shown
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!("1h30m".parse(), Ok(Duration(time::Duration::from_secs(5_400))));
+        assert_eq!("250ms".parse(), Ok(Duration(time::Duration::new(0, 250_000_000))));
+        assert_eq!(round_trip::<Duration>("90m"), "1h30m");
+        assert_eq!(round_trip::<Duration>("0s"), "0s");
+        assert_eq!(round_trip::<Duration>("1d1ns"), "1d1ns");
+        assert_eq!(round_trip::<Duration>("300000d"), "300000d");
+        assert_eq!(round_trip::<Duration>("1500ms2000000000ns"), "3s500ms");
+    }
+
+    #[test]
+    fn longest_duration_round_trips() {
+        let longest = Duration(time::Duration::new(::std::u64::MAX, 999_999_999));
+        assert_eq!(longest.to_string().parse(), Ok(longest));
+    }
+
+    #[test]
+    fn malformed_durations() {
+        assert!("".parse::<Duration>().is_err());
+        assert!("10".parse::<Duration>().is_err());
+        assert!("m".parse::<Duration>().is_err());
+        assert!("10x".parse::<Duration>().is_err());
+        assert!("1.5h".parse::<Duration>().is_err());
+        assert!("99999999999999999999d".parse::<Duration>().is_err());
+        assert!("213503982334602d".parse::<Duration>().is_err());
+        assert!("18446744073709551615s1000ms".parse::<Duration>().is_err());
+    }
+
+    #[test]
+    fn byte_sizes() {
+        assert_eq!("512".parse(), Ok(ByteSize(512)));
+        assert_eq!("10MiB".parse(), Ok(ByteSize(10 * 1024 * 1024)));
+        assert_eq!("1.5G".parse(), Ok(ByteSize(3 * 512 * 1024 * 1024)));
+        assert_eq!("2kb".parse(), Ok(ByteSize(2_000)));
+        assert_eq!(round_trip::<ByteSize>("2048"), "2KiB");
+        assert_eq!(round_trip::<ByteSize>("2kb"), "2000B");
+        assert_eq!(round_trip::<ByteSize>("0"), "0B");
+    }
+
+    #[test]
+    fn malformed_byte_sizes() {
+        assert!("".parse::<ByteSize>().is_err());
+        assert!("MiB".parse::<ByteSize>().is_err());
+        assert!("10XB".parse::<ByteSize>().is_err());
+        assert!("10ib".parse::<ByteSize>().is_err());
+        assert!("0.1B".parse::<ByteSize>().is_err());
+        assert!("16384P".parse::<ByteSize>().is_err());
+        assert!("99999999999999999999".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn range_lists() {
+        let list: RangeList = "7,0-3,2-4".parse().unwrap();
+        assert_eq!(list.ranges(), &[(0, 4), (7, 7)]);
+        assert_eq!(list.values(), vec![0, 1, 2, 3, 4, 7]);
+        assert!(list.contains(3) && !list.contains(5));
+        assert_eq!(round_trip::<RangeList>("3,1-2,5"), "1-3,5");
+    }
+
+    #[test]
+    fn range_lists_reach_the_maximum() {
+        let max = u64::max_value();
+        let list: RangeList = format!("{}-{}", max - 1, max).parse().unwrap();
+        assert_eq!(list.values(), vec![max - 1, max]);
+        let list: RangeList = format!("0,{}", max).parse().unwrap();
+        assert_eq!(list.ranges(), &[(0, 0), (max, max)]);
+    }
+
+    #[test]
+    fn malformed_range_lists() {
+        assert!("".parse::<RangeList>().is_err());
+        assert!("1,,2".parse::<RangeList>().is_err());
+        assert!("3-1".parse::<RangeList>().is_err());
+        assert!("a-2".parse::<RangeList>().is_err());
+        assert!("18446744073709551616".parse::<RangeList>().is_err());
+    }
+
+    #[test]
+    fn percentages() {
+        let percentage: Percentage = "50%".parse().unwrap();
+        assert_eq!(percentage.percent(), 50.0);
+        assert_eq!(percentage.ratio(), 0.5);
+        assert_eq!("12.5".parse(), Ok(Percentage(12.5)));
+        assert_eq!(round_trip::<Percentage>("12.5"), "12.5%");
+    }
+
+    #[test]
+    fn malformed_percentages() {
+        assert!("".parse::<Percentage>().is_err());
+        assert!("%".parse::<Percentage>().is_err());
+        assert!("-5%".parse::<Percentage>().is_err());
+        assert!("inf".parse::<Percentage>().is_err());
+        assert!("NaN%".parse::<Percentage>().is_err());
+        assert!("5%%".parse::<Percentage>().is_err());
+    }
+}
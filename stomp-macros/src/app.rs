@@ -0,0 +1,45 @@
+use syn;
+use quote;
+
+use attrs::Attributes;
+
+/// The App settings shared by commands and `StompCommands` variants, such as
+/// `settings(..)`, `usage` and `template`.
+pub fn expand_settings(attrs: &Attributes) -> quote::Tokens {
+    let settings = attrs.get("settings").map(|a| {
+        let settings = a.values().into_iter().map(syn::Ident::from);
+        quote! { .settings(&[#(::clap::AppSettings::#settings),*]) }
+    });
+    let global_settings = attrs.get("global_settings").map(|a| {
+        let settings = a.values().into_iter().map(syn::Ident::from);
+        quote! { .global_settings(&[#(::clap::AppSettings::#settings),*]) }
+    });
+    let alias = attrs.get("alias").map(|a| {
+        let aliases = a.values();
+        quote! { .aliases(&[#(#aliases),*]) }
+    });
+    let usage = attrs.get("usage").map(|a| quote! { .usage(#a) });
+    let template = attrs.get("template").map(|a| quote! { .template(#a) });
+    let before_help = attrs.get("before_help").map(|a| quote! { .before_help(#a) });
+    let help_short = attrs.get("help_short").map(|a| {
+        let short = (a.into(): char).to_string();
+        quote! { .help_short(#short) }
+    });
+    let version_short = attrs.get("version_short").map(|a| {
+        let short = (a.into(): char).to_string();
+        quote! { .version_short(#short) }
+    });
+    let bin_name = attrs.get("bin_name").map(|a| quote! { .bin_name(#a) });
+
+    quote! {
+        #settings
+        #global_settings
+        #alias
+        #usage
+        #template
+        #before_help
+        #help_short
+        #version_short
+        #bin_name
+    }
+}
@@ -1,20 +1,43 @@
+use std::cell::Cell;
+
 use syn;
 use quote;
 
+use attrs::Attributes;
+
 pub struct Attribute {
     key: String,
     values: Vec<syn::Lit>,
+    nested: Vec<Attributes>,
+    nested_used: Cell<bool>,
 }
 
 impl Attribute {
     pub fn new(key: String) -> Attribute {
-        Attribute { key: key, values: vec![] }
+        Attribute { key: key, values: vec![], nested: vec![], nested_used: Cell::new(false) }
     }
 
     pub fn push(&mut self, value: syn::Lit) {
         self.values.push(value)
     }
 
+    pub fn push_nested(&mut self, nested: Attributes) {
+        self.nested.push(nested)
+    }
+
+    /// The attributes of each sublist given for this key, e.g.
+    /// `#[stomp(group(name = "a"), group(name = "b"))]` has two.
+    pub fn nested(&self) -> &[Attributes] {
+        self.nested_used.set(true);
+        &self.nested
+    }
+
+    /// Whether the sublists were accessed as nested attributes, rather than as
+    /// a list of plain values.
+    pub fn nested_used(&self) -> bool {
+        self.nested_used.get()
+    }
+
     pub fn values(&self) -> Vec<String> {
         self.values.iter().map(|s| match *s {
             syn::Lit::Str(ref s, _) => s.clone(),
@@ -2,6 +2,7 @@ use std::cell::RefCell;
 use std::collections::{ BTreeMap, HashMap };
 
 use syn;
+use quote;
 
 use attr::Attribute;
 
@@ -12,19 +13,26 @@ pub struct Attributes {
 }
 
 pub struct FieldAttributes {
+    kind: &'static str,
     empty: Attributes,
     map: HashMap<syn::Ident, (RefCell<usize>, Attributes)>,
 }
 
 impl Attributes {
-    pub fn check_used(&self, name: &str, field: Option<&str>) {
-        for (ref attr, &(ref counter, _)) in &self.map {
-            if *counter.borrow() == 0 {
+    pub fn check_used(&self, name: &str, field: Option<(&str, &str)>) {
+        for (ref attr, &(ref counter, ref value)) in &self.map {
+            if *counter.borrow() != 0 {
+                if value.nested_used() {
+                    for nested in value.nested() {
+                        nested.check_used(name, field);
+                    }
+                }
+            } else {
                 match field {
-                    Some(field) =>
-                        println!("stomp-macros: unexpected attribute '{}' on field '{}' of struct '{}'", attr, field, name),
+                    Some((kind, field)) =>
+                        println!("stomp-macros: unexpected attribute '{}' on {} '{}' of '{}'", attr, kind, field, name),
                     None =>
-                        println!("stomp-macros: unexpected attribute '{}' on struct '{}'", attr, name),
+                        println!("stomp-macros: unexpected attribute '{}' on '{}'", attr, name),
                 }
             }
         }
@@ -42,20 +50,39 @@ impl Attributes {
     pub fn get_bool(&self, key: &str) -> bool {
         self.get(key).map(|a| a.into()).unwrap_or(false)
     }
+
+    /// Accesses every attribute, for sublists where the keys are user defined
+    /// e.g. `#[stomp(requires_if(field = "value"))]`
+    pub fn all(&self) -> Vec<(&str, &Attribute)> {
+        self.map.iter()
+            .map(|(key, &(ref counter, ref attr))| {
+                *counter.borrow_mut() += 1;
+                (key.as_ref(), attr)
+            })
+            .collect()
+    }
 }
 
 impl FieldAttributes {
     pub fn check_used(&self, name: &str) {
         for (ref field, &(ref counter, ref attrs)) in &self.map {
             if *counter.borrow() == 0 {
-                panic!("stomp-macros: didn't access attributes for field '{}' on struct '{}' for some reason", field, name);
+                panic!("stomp-macros: didn't access attributes for {} '{}' on '{}' for some reason", self.kind, field, name);
             }
-            attrs.check_used(name, Some(field.as_ref()));
+            attrs.check_used(name, Some((self.kind, field.as_ref())));
         }
     }
 
     pub fn get(&self, field: &syn::Field) -> &Attributes {
-        if let Some(&(ref counter, ref attrs)) = self.map.get(field.ident.as_ref().unwrap()) {
+        self.get_ident(field.ident.as_ref().unwrap())
+    }
+
+    pub fn get_variant(&self, variant: &syn::Variant) -> &Attributes {
+        self.get_ident(&variant.ident)
+    }
+
+    fn get_ident(&self, ident: &syn::Ident) -> &Attributes {
+        if let Some(&(ref counter, ref attrs)) = self.map.get(ident) {
             *counter.borrow_mut() += 1;
             attrs
         } else {
@@ -82,14 +109,20 @@ fn extract_attrs_inner(attrs: &mut Vec<syn::Attribute>) -> Attributes {
                             }
                             syn::MetaItem::List(ref ident, ref values) => {
                                 let &mut (_, ref mut attr) = stomps.entry(ident.as_ref().to_string()).or_insert((RefCell::new(0), Attribute::new(ident.as_ref().to_string())));
+                                // Each sublist is also kept as its own set of attributes, for
+                                // things like #[stomp(group(name = "mode", required))]
+                                let mut nested = BTreeMap::new();
                                 for value in values {
                                     match *value {
                                         syn::NestedMetaItem::MetaItem(ref item) => match *item {
                                             syn::MetaItem::Word(ref name) => {
                                                 attr.push(name.as_ref().into());
+                                                let &mut (_, ref mut nested) = nested.entry(name.to_string()).or_insert((RefCell::new(0), Attribute::new(name.to_string())));
+                                                nested.push(syn::Lit::Bool(true));
                                             }
-                                            syn::MetaItem::NameValue(..) => {
-                                                panic!("Invalid stomp attribute {} named value in sublist not supported", quote!(#attr).to_string().replace(" ", ""));
+                                            syn::MetaItem::NameValue(ref name, ref value) => {
+                                                let &mut (_, ref mut nested) = nested.entry(name.to_string()).or_insert((RefCell::new(0), Attribute::new(name.to_string())));
+                                                nested.push(value.clone());
                                             }
                                             syn::MetaItem::List(..) => {
                                                 panic!("Invalid stomp attribute {} sublist in sublist not supported", quote!(#attr).to_string().replace(" ", ""));
@@ -100,6 +133,7 @@ fn extract_attrs_inner(attrs: &mut Vec<syn::Attribute>) -> Attributes {
                                         },
                                     }
                                 }
+                                attr.push_nested(Attributes { summary: "".into(), docs: "".into(), map: nested });
                             }
                         },
                         syn::NestedMetaItem::Literal(_) => {
@@ -116,43 +150,237 @@ fn extract_attrs_inner(attrs: &mut Vec<syn::Attribute>) -> Attributes {
         }
     });
 
-    let docs = attrs.iter()
+    let lines: Vec<&str> = attrs.iter()
         .filter(|a| a.is_sugared_doc)
         .map(|a| match a.value {
-            syn::MetaItem::NameValue(_, syn::Lit::Str(ref doc, _)) => doc,
+            syn::MetaItem::NameValue(_, syn::Lit::Str(ref doc, _)) => {
+                // Only the comment marker and the space after it are removed,
+                // indentation matters for lists and code blocks
+                let line = doc.trim_left_matches('/');
+                if line.starts_with(' ') { &line[1..] } else { line }
+            }
             _ => unreachable!(),
         })
-        .fold(String::new(), |docs, line| docs + line.trim_left_matches('/').trim() + "\n");
-
-    let index = docs.find("\n\n");
-    let (summary, docs) = if let Some(index) = index {
-        let (summary, docs) = docs.split_at(index);
-        let (_, docs) = docs.split_at(2);
-        (summary.into(), docs.into())
-    } else {
-        (docs, "".into())
-    };
+        .collect();
+    let (summary, docs) = render_docs(&lines);
 
     Attributes { summary: summary, docs: docs, map: stomps }
 }
 
+/// Removes basic inline markdown: paired emphasis, code spans and links.
+/// Anything unpaired, like the `*` in `*.rs`, `a * b` or a list marker, is
+/// kept as it is.
+fn strip_markdown(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut stripped = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '*' || c == '`' {
+            let run = chars[i..].iter().take_while(|&&r| r == c).count();
+            if let Some(end) = closing(&chars, i + run, c, run) {
+                let inner: String = chars[(i + run)..end].iter().cloned().collect();
+                // Code is kept verbatim
+                if c == '`' {
+                    stripped.push_str(inner.trim());
+                } else {
+                    stripped.push_str(&strip_markdown(&inner));
+                }
+                i = end + run;
+            } else {
+                stripped.extend(&chars[i..(i + run)]);
+                i += run;
+            }
+        } else if c == '[' {
+            // [text](url) keeps just the text
+            let link = chars[i..].iter().position(|&c| c == ']').map(|end| i + end)
+                .and_then(|end| {
+                    if chars.get(end + 1) == Some(&'(') {
+                        chars[end..].iter().position(|&c| c == ')').map(|close| (end, end + close))
+                    } else {
+                        None
+                    }
+                });
+            match link {
+                Some((end, close)) => {
+                    let text: String = chars[(i + 1)..end].iter().cloned().collect();
+                    stripped.push_str(&strip_markdown(&text));
+                    i = close + 1;
+                }
+                None => {
+                    stripped.push('[');
+                    i += 1;
+                }
+            }
+        } else {
+            stripped.push(c);
+            i += 1;
+        }
+    }
+    stripped
+}
+
+/// Finds the run of exactly `run` delimiters closing one that ends at
+/// `start`. Emphasis can't start before or end after whitespace.
+fn closing(chars: &[char], start: usize, delimiter: char, run: usize) -> Option<usize> {
+    if delimiter == '*' && chars.get(start).map_or(true, |c| c.is_whitespace()) {
+        return None;
+    }
+    let mut i = start;
+    while i < chars.len() {
+        if chars[i] == delimiter {
+            let len = chars[i..].iter().take_while(|&&c| c == delimiter).count();
+            if len == run && i > start && (delimiter == '`' || !chars[i - 1].is_whitespace()) {
+                return Some(i);
+            }
+            i += len;
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+fn is_list_item(line: &str) -> bool {
+    let line = line.trim_left();
+    if line.starts_with("- ") || line.starts_with("* ") || line.starts_with("+ ") {
+        return true;
+    }
+    let digits = line.chars().take_while(|c| c.is_digit(10)).count();
+    digits > 0 && line[digits..].starts_with(". ")
+}
+
+/// Splits doc comment lines into the summary (the first paragraph) and the
+/// rest of the docs.
+///
+/// Paragraphs are joined into single lines, list items keep their own lines
+/// and code blocks (indented or fenced) are kept verbatim. Headings and basic
+/// inline markdown are stripped outside of code.
+fn render_docs(lines: &[&str]) -> (String, String) {
+    let mut blocks: Vec<String> = Vec::new();
+    let mut current: Vec<String> = Vec::new();
+    let mut in_paragraph = false;
+    let mut in_fence = false;
+
+    fn finish(blocks: &mut Vec<String>, current: &mut Vec<String>) {
+        if !current.is_empty() {
+            blocks.push(current.join("\n"));
+            current.clear();
+        }
+    }
+
+    for &line in lines {
+        if line.trim_left().starts_with("```") {
+            if !in_fence {
+                finish(&mut blocks, &mut current);
+            }
+            in_fence = !in_fence;
+            in_paragraph = false;
+            if !in_fence {
+                finish(&mut blocks, &mut current);
+            }
+            continue;
+        }
+        if in_fence {
+            current.push(format!("    {}", line));
+            continue;
+        }
+        if line.trim().is_empty() {
+            finish(&mut blocks, &mut current);
+            in_paragraph = false;
+        } else if line.starts_with("    ") && !in_paragraph {
+            // An indented code block, or a continuation of a list item
+            if current.last().map_or(false, |last| is_list_item(last)) && !line.starts_with("      ") {
+                let last = current.pop().unwrap();
+                current.push(last + " " + &strip_markdown(line.trim()));
+            } else {
+                current.push(line.to_owned());
+            }
+        } else if line.starts_with('#') {
+            finish(&mut blocks, &mut current);
+            in_paragraph = false;
+            blocks.push(strip_markdown(line.trim_left_matches('#').trim()));
+        } else if is_list_item(line) {
+            in_paragraph = false;
+            let indent = line.len() - line.trim_left().len();
+            let indent: String = ::std::iter::repeat(' ').take(indent).collect();
+            current.push(indent + &strip_markdown(line.trim()));
+        } else {
+            let text = strip_markdown(line.trim());
+            // Lines following a paragraph or list item without a blank line
+            // continue it
+            if in_paragraph || current.last().map_or(false, |last| is_list_item(last)) {
+                let last = current.pop().unwrap();
+                current.push(last + " " + &text);
+            } else {
+                current.push(text);
+                in_paragraph = true;
+            }
+        }
+    }
+    finish(&mut blocks, &mut current);
+
+    let mut blocks = blocks.into_iter();
+    let summary = blocks.next().unwrap_or_else(String::new);
+    let docs = blocks.collect::<Vec<_>>().join("\n\n");
+    (summary, docs)
+}
+
+/// Help that's just the summary for `-h` and the full docs for `--help`.
+pub fn expand_help(short: &str, long: &str) -> quote::Tokens {
+    if short == long {
+        quote! { #short }
+    } else {
+        quote! { if ::stomp::long_help() { #long } else { #short } }
+    }
+}
+
 /// Extracts all stomp attributes of the form #[stomp(i = V)]
 pub fn extract_attrs(ast: &mut syn::MacroInput) -> (Attributes, FieldAttributes) {
     let empty = Attributes { summary: "".into(), docs: "".into(), map: BTreeMap::new() };
     let root_attrs = extract_attrs_inner(&mut ast.attrs);
-    let field_attrs = match ast.body {
+    let (kind, field_attrs) = match ast.body {
         syn::Body::Struct(syn::VariantData::Struct(ref mut fields)) => {
-            fields
+            ("field", fields
                 .iter_mut()
                 .map(|field| (field.ident.clone().unwrap(), (RefCell::new(0), extract_attrs_inner(&mut field.attrs))))
-                .collect()
+                .collect())
         }
         syn::Body::Struct(syn::VariantData::Tuple(_)) => {
             panic!("TODO: tuple struct unsupported msg")
         }
-        syn::Body::Struct(syn::VariantData::Unit) | syn::Body::Enum(_) => {
-            HashMap::new()
+        syn::Body::Struct(syn::VariantData::Unit) => {
+            ("field", HashMap::new())
+        }
+        syn::Body::Enum(ref mut variants) => {
+            ("variant", variants
+                .iter_mut()
+                .map(|variant| (variant.ident.clone(), (RefCell::new(0), extract_attrs_inner(&mut variant.attrs))))
+                .collect())
         }
     };
-    (root_attrs, FieldAttributes { empty: empty, map: field_attrs })
+    (root_attrs, FieldAttributes { kind: kind, empty: empty, map: field_attrs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ render_docs, strip_markdown };
+
+    #[test]
+    fn paired_markdown_is_stripped() {
+        assert_eq!(strip_markdown("a *b* **c** `d*e*` [f](http://g)"), "a b c d*e* f");
+    }
+
+    #[test]
+    fn unpaired_markdown_is_kept() {
+        assert_eq!(strip_markdown("globs like *.rs and *.toml"), "globs like *.rs and *.toml");
+        assert_eq!(strip_markdown("a * b * c"), "a * b * c");
+        assert_eq!(strip_markdown("[not a link] and `tick"), "[not a link] and `tick");
+    }
+
+    #[test]
+    fn list_markers_are_kept() {
+        let (_, docs) = render_docs(&["Summary.", "", "* one", "* *two*"]);
+        assert_eq!(docs, "* one\n* two");
+    }
 }
@@ -1,10 +1,14 @@
 use syn;
+use quote;
 
 use attrs::Attributes;
 
 pub enum Field<'a> {
     Arg(Arg<'a>),
     Subcommand(Subcommand<'a>),
+    Mode(Mode<'a>),
+    Skip(Skip<'a>),
+    Unknown(Unknown<'a>),
 }
 
 pub struct Arg<'a> {
@@ -12,9 +16,19 @@ pub struct Arg<'a> {
     pub name: &'a str,
     pub ty: &'a syn::Ty,
     pub short: Option<String>,
-    pub long: Option<&'a str>,
-    pub value_name: Option<&'a str>,
+    /// Whether to derive `short` from the long name
+    pub auto_short: bool,
+    /// Whether to get a short name when the command is built, if one is free
+    pub wants_short: bool,
+    pub long: Option<String>,
+    pub value_name: Option<String>,
     pub index: Option<u64>,
+    /// Positional arguments without an index get one in field order
+    pub is_positional: bool,
+    /// The field's position, used to keep help in declaration order
+    pub display_order: usize,
+    /// Shows the argument in its own section of help
+    pub help_heading: Option<&'a str>,
     pub summary: &'a str,
     pub docs: &'a str,
     pub takes_value: bool,
@@ -23,18 +37,128 @@ pub struct Arg<'a> {
     pub is_optional: bool,
     pub required: bool,
     pub default_value: Option<&'a str>,
+    /// A typed default, either the `default` expression or a call to the
+    /// `default_fn`
+    pub default: Option<quote::Tokens>,
     pub min_values: Option<u64>,
     pub max_values: Option<u64>,
+    pub group: Option<&'a str>,
+    pub conflicts_with: Vec<String>,
+    pub requires: Vec<String>,
+    pub required_unless: Vec<String>,
+    pub requires_if: Vec<(String, &'a str)>,
+    pub env: Option<String>,
+    pub is_early: bool,
+    pub hidden: bool,
+    pub aliases: Vec<String>,
+    pub visible_aliases: Vec<String>,
+    pub deprecated: Option<&'a str>,
+    /// Old long names that still work but warn when used
+    pub deprecated_aliases: Vec<String>,
+    /// Constraints checked along with parsing each value
+    pub range: Option<&'a str>,
+    pub pattern: Option<&'a str>,
+    pub min_len: Option<u64>,
+    pub max_len: Option<u64>,
+    pub validator: Option<&'a str>,
+    /// Captures all trailing arguments verbatim, as `String`s or `OsString`s
+    pub is_raw: bool,
+    pub is_os_string: bool,
 }
 
 pub struct Subcommand<'a> {
     pub ident: &'a syn::Ident,
     pub ty: &'a syn::Ty,
     pub is_optional: bool,
+    pub is_vec: bool,
+    pub default: Option<String>,
+}
+
+pub struct Mode<'a> {
+    pub ident: &'a syn::Ident,
+    pub ty: &'a syn::Ty,
+    pub is_optional: bool,
+    /// A `Vec` of every occurrence of the mode's arguments in order, rather
+    /// than a choice of one of them
+    pub is_sequence: bool,
+}
+
+/// A field that isn't parsed, filled with its `default` expression or
+/// `Default::default()`
+pub struct Skip<'a> {
+    pub ident: &'a syn::Ident,
+    pub default: Option<quote::Tokens>,
+}
+
+/// A `Vec<OsString>` field collecting any options the command doesn't know
+pub struct Unknown<'a> {
+    pub ident: &'a syn::Ident,
+}
+
+/// How long names are derived from field names, set by the command's
+/// `rename_all`
+#[derive(Clone, Copy)]
+pub enum Rename {
+    KebabCase,
+    SnakeCase,
+    Verbatim,
+}
+
+impl Rename {
+    pub fn from_attrs(attrs: &Attributes) -> Rename {
+        match attrs.get("rename_all").map(|a| a.into(): &str) {
+            None | Some("kebab-case") => Rename::KebabCase,
+            Some("snake_case") => Rename::SnakeCase,
+            Some("verbatim") => Rename::Verbatim,
+            Some(other) => panic!("stomp-macros: unknown rename_all '{}', expected kebab-case, snake_case or verbatim", other),
+        }
+    }
+
+    pub fn apply(&self, name: &str) -> String {
+        let separator = match *self {
+            Rename::KebabCase => "-",
+            Rename::SnakeCase => "_",
+            Rename::Verbatim => return name.to_owned(),
+        };
+        words(name).join(separator)
+    }
+}
+
+/// e.g. `DEBUG_LEVEL` for `debug_level`, used for value names
+pub fn screaming_case(name: &str) -> String {
+    words(name).join("_").to_uppercase()
+}
+
+/// Splits a name into lowercase words at underscores, hyphens and the start
+/// of each capitalised word.
+fn words(name: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut previous_lower = false;
+    for c in name.chars() {
+        if c == '_' || c == '-' {
+            if !word.is_empty() {
+                words.push(word);
+                word = String::new();
+            }
+            previous_lower = false;
+            continue;
+        }
+        if c.is_uppercase() && previous_lower {
+            words.push(word);
+            word = String::new();
+        }
+        previous_lower = c.is_lowercase() || c.is_digit(10);
+        word.extend(c.to_lowercase());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
 }
 
 impl<'a> Field<'a> {
-    pub fn arg(&self) -> Option<&Arg> {
+    pub fn arg(&self) -> Option<&Arg<'a>> {
         if let Field::Arg(ref arg) = *self {
             Some(arg)
         } else {
@@ -42,41 +166,68 @@ impl<'a> Field<'a> {
         }
     }
 
-    pub fn subcommand(&self) -> Option<&Subcommand> {
+    pub fn subcommand(&self) -> Option<&Subcommand<'a>> {
         if let Field::Subcommand(ref subcommand) = *self {
             Some(subcommand)
         } else {
             None
         }
     }
+
+    pub fn mode(&self) -> Option<&Mode<'a>> {
+        if let Field::Mode(ref mode) = *self {
+            Some(mode)
+        } else {
+            None
+        }
+    }
 }
 
-impl<'a> From<(&'a syn::Field, &'a Attributes)> for Field<'a> {
-    fn from((field, attrs): (&'a syn::Field, &'a Attributes)) -> Field<'a> {
-        if attrs.get_bool("subcommand") {
-            Field::Subcommand(Subcommand::from(field))
+impl<'a> From<(&'a syn::Field, &'a Attributes, Rename)> for Field<'a> {
+    fn from((field, attrs, rename): (&'a syn::Field, &'a Attributes, Rename)) -> Field<'a> {
+        if attrs.get_bool("skip") {
+            Field::Skip(Skip::from((field, attrs)))
+        } else if attrs.get_bool("unknown") {
+            Field::Unknown(Unknown::from(field))
+        } else if attrs.get_bool("subcommand") {
+            Field::Subcommand(Subcommand::from((field, attrs)))
+        } else if attrs.get_bool("mode") {
+            Field::Mode(Mode::from((field, false)))
+        } else if attrs.get_bool("sequence") {
+            Field::Mode(Mode::from((field, true)))
         } else {
-            Field::Arg(Arg::from((field, attrs)))
+            Field::Arg(Arg::from((field, attrs, rename)))
         }
     }
 }
 
-impl<'a> From<(&'a syn::Field, &'a Attributes)> for Arg<'a> {
-    fn from((field, attrs): (&'a syn::Field, &'a Attributes)) -> Arg<'a> {
+impl<'a> From<(&'a syn::Field, &'a Attributes, Rename)> for Arg<'a> {
+    fn from((field, attrs, rename): (&'a syn::Field, &'a Attributes, Rename)) -> Arg<'a> {
         let name = attrs.get("name").map(|a| a.into())
                 .unwrap_or_else(|| field.ident.as_ref().unwrap().as_ref());
 
         let index = attrs.get("index").map(|a| a.into(): u64);
+        let is_raw = attrs.get_bool("raw") | attrs.get_bool("last");
 
         // Unlike clap we default to a flag option unless there's a attribute given
         // telling us to not do so
-        let is_flag = !index.is_some() && !attrs.get_bool("arg");
+        let is_flag = !index.is_some() && !attrs.get_bool("arg") && !is_raw;
 
-        let long = attrs.get("long").map(|a| a.into())
-            .or_else(|| if is_flag { Some(name) } else { None });
+        let long = attrs.get("long").map(|a| (a.into(): &str).to_owned())
+            .or_else(|| if is_flag { Some(rename.apply(name)) } else { None });
 
-        let short = attrs.get("short").map(|s| (s.into(): char).to_string());
-        let value_name = attrs.get("value_name").map(|a| a.into());
+        // A bare #[stomp(short)] is derived from the long name once all
+        // fields are known, so it can avoid collisions
+        let (short, auto_short) = match attrs.get("short").map(|a| a.into(): &syn::Lit) {
+            Some(&syn::Lit::Char(short)) => (Some(short.to_string()), false),
+            Some(&syn::Lit::Bool(true)) => (None, true),
+            Some(lit) => panic!("Expected char value for attribute short but got a {:?}", lit),
+            None => (None, false),
+        };
+        if auto_short && long.is_none() {
+            panic!("stomp-macros: short can only be derived for fields with a long name, not '{}'", field.ident.as_ref().unwrap().as_ref());
+        }
+        let value_name = attrs.get("value_name").map(|a| (a.into(): &str).to_owned());
 
         let is_counter = attrs.get_bool("counted");
 
@@ -99,20 +250,133 @@ impl<'a> From<(&'a syn::Field, &'a Attributes)> for Arg<'a> {
             _ => panic!("unsupported field type {:?}", field.ty),
         };
 
+        let is_string = match *ty {
+            syn::Ty::Path(None, ref path) => path.segments.last().unwrap().ident == "String",
+            _ => false,
+        };
+        let is_os_string = match *ty {
+            syn::Ty::Path(None, ref path) => path.segments.last().unwrap().ident == "OsString",
+            _ => false,
+        };
+        if is_raw && !(is_vec && (is_string || is_os_string)) {
+            panic!("stomp-macros: raw fields must be a Vec<String> or Vec<OsString>, not '{}'", field.ident.as_ref().unwrap().as_ref());
+        }
+
+        // Hint at what values some types expect, otherwise use the name
+        let value_name = value_name.or_else(|| {
+            let hint = match *ty {
+                syn::Ty::Path(None, ref path) => match path.segments.last().unwrap().ident.as_ref() {
+                    "PathBuf" | "ExistingFile" | "NewFile" => Some("FILE"),
+                    "ExistingDir" => Some("DIR"),
+                    "Input" => Some("INPUT"),
+                    "Output" => Some("OUTPUT"),
+                    "u8" | "u16" | "u32" | "u64" | "usize" | "i8" | "i16" | "i32" | "i64" | "isize" => Some("N"),
+                    "f32" | "f64" => Some("NUM"),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if is_bool || is_counter || is_raw {
+                None
+            } else {
+                Some(hint.map(|hint| hint.to_owned()).unwrap_or_else(|| screaming_case(name)))
+            }
+        });
+
         let multiple = is_counter || is_vec;
         let default_value = attrs.get("default_value").map(|a| a.into());
         let min_values = attrs.get("min_values").map(|a| a.into());
         let max_values = attrs.get("max_values").map(|a| a.into());
 
-        let required = !is_bool && !is_optional;
+        let default = match (attrs.get("default"), attrs.get("default_fn")) {
+            (Some(expr), None) => {
+                let mut tokens = quote::Tokens::new();
+                tokens.append(expr.into());
+                Some(tokens)
+            }
+            (None, Some(path)) => {
+                let path = syn::parse_path(path.into()).expect("stomp-macros: invalid default_fn path");
+                Some(quote! { #path() })
+            }
+            (Some(_), Some(_)) => {
+                panic!("stomp-macros: field '{}' can't have both default and default_fn", field.ident.as_ref().unwrap().as_ref());
+            }
+            (None, None) => None,
+        };
+        if default.is_some() && (default_value.is_some() || is_optional || is_vec || is_bool || is_counter) {
+            panic!("stomp-macros: default and default_fn are only supported on single valued fields without a default_value, not on '{}'", field.ident.as_ref().unwrap().as_ref());
+        }
+
+        let required = !is_bool && !is_optional && default.is_none() && !is_raw;
+
+        // A bare #[stomp(env)] uses the uppercased name, the struct's
+        // env_prefix gets added once all fields are known
+        let env = attrs.get("env").map(|a| match a.into(): &syn::Lit {
+            &syn::Lit::Bool(true) => name.to_uppercase(),
+            &syn::Lit::Str(ref var, _) => var.clone(),
+            lit => panic!("Expected string value for attribute env but got a {:?}", lit),
+        });
+        if env.is_some() && is_vec {
+            panic!("stomp-macros: env is not supported on multi-valued field '{}'", field.ident.as_ref().unwrap().as_ref());
+        }
+
+        let is_early = attrs.get_bool("early");
+        if is_early && !is_flag {
+            panic!("stomp-macros: early is not supported on positional field '{}'", field.ident.as_ref().unwrap().as_ref());
+        }
+
+        // Deprecated arguments still work but aren't shown in help
+        let deprecated = attrs.get("deprecated").map(|a| a.into());
+        let hidden = attrs.get_bool("hidden") || deprecated.is_some();
+        let aliases = attrs.get("alias").map(|a| a.values()).unwrap_or_else(Vec::new);
+        let visible_aliases = attrs.get("visible_alias").map(|a| a.values()).unwrap_or_else(Vec::new);
+        let deprecated_aliases = attrs.get("deprecated_alias").map(|a| a.values()).unwrap_or_else(Vec::new);
+        if (!aliases.is_empty() || !visible_aliases.is_empty() || !deprecated_aliases.is_empty()) && long.is_none() {
+            panic!("stomp-macros: aliases are only supported on long options, not on field '{}'", field.ident.as_ref().unwrap().as_ref());
+        }
+
+        let range = attrs.get("range").map(|a| a.into());
+        let pattern = attrs.get("pattern").map(|a| a.into());
+        let min_len = attrs.get("min_len").map(|a| a.into());
+        let max_len = attrs.get("max_len").map(|a| a.into());
+        let validator = attrs.get("validator").map(|a| a.into());
+        let has_constraints = range.is_some() || pattern.is_some() || min_len.is_some() || max_len.is_some() || validator.is_some();
+        if has_constraints && (is_bool || is_counter) {
+            panic!("stomp-macros: value constraints are only supported on fields taking values, not on '{}'", field.ident.as_ref().unwrap().as_ref());
+        }
+
+        // These all refer to other fields by their identifier, they're
+        // resolved to the clap names once all fields are known
+        let group = attrs.get("group").map(|a| a.into());
+        let conflicts_with = attrs.get("conflicts_with").map(|a| a.values()).unwrap_or_else(Vec::new);
+        let requires = attrs.get("requires").map(|a| a.values()).unwrap_or_else(Vec::new);
+        let required_unless = attrs.get("required_unless").map(|a| a.values()).unwrap_or_else(Vec::new);
+        let requires_if = attrs.get("requires_if")
+            .map(|a| {
+                a.nested().iter()
+                    .flat_map(|nested| nested.all())
+                    .map(|(field, value)| (field.to_owned(), value.into()))
+                    .collect()
+            })
+            .unwrap_or_else(Vec::new);
+        // Either of these lets the field be missing, which its type has to
+        // be able to represent
+        if required && !is_counter && default_value.is_none() && (group.is_some() || !required_unless.is_empty()) {
+            panic!("stomp-macros: field '{}' may be missing so it must be an Option or have a default", field.ident.as_ref().unwrap().as_ref());
+        }
 
         Arg {
             ident: field.ident.as_ref().unwrap(),
             ty: ty,
             name: name,
             short: short,
+            auto_short: auto_short,
+            wants_short: false,
             long: long,
             index: index,
+            is_positional: !is_flag,
+            display_order: 0,
+            help_heading: attrs.get("help_heading").map(|a| a.into()),
             value_name: value_name,
             summary: &attrs.summary,
             docs: &attrs.docs,
@@ -122,19 +386,40 @@ impl<'a> From<(&'a syn::Field, &'a Attributes)> for Arg<'a> {
             is_optional: is_optional,
             required: required,
             default_value: default_value,
+            default: default,
             min_values: min_values,
             max_values: max_values,
+            group: group,
+            conflicts_with: conflicts_with,
+            requires: requires,
+            required_unless: required_unless,
+            requires_if: requires_if,
+            env: env,
+            is_early: is_early,
+            hidden: hidden,
+            aliases: aliases,
+            visible_aliases: visible_aliases,
+            deprecated: deprecated,
+            deprecated_aliases: deprecated_aliases,
+            range: range,
+            pattern: pattern,
+            min_len: min_len,
+            max_len: max_len,
+            validator: validator,
+            is_raw: is_raw,
+            is_os_string: is_os_string,
         }
     }
 }
 
-impl<'a> From<&'a syn::Field> for Subcommand<'a> {
-    fn from(field: &'a syn::Field) -> Subcommand<'a> {
-        let (is_optional, ty);
+impl<'a> From<(&'a syn::Field, &'a Attributes)> for Subcommand<'a> {
+    fn from((field, attrs): (&'a syn::Field, &'a Attributes)) -> Subcommand<'a> {
+        let (is_optional, is_vec, ty);
         match field.ty {
             syn::Ty::Path(None, ref path) => {
                 is_optional = path.segments[0].ident == "Option";
-                if is_optional {
+                is_vec = path.segments[0].ident == "Vec";
+                if is_optional || is_vec {
                     if let syn::PathParameters::AngleBracketed(ref params) = path.segments[0].parameters {
                         ty = &params.types[0];
                     } else {
@@ -147,10 +432,85 @@ impl<'a> From<&'a syn::Field> for Subcommand<'a> {
             _ => panic!("unsupported field type {:?}", field.ty),
         };
 
+        // Subcommands are matched by their lowercased variant name
+        let default = attrs.get("default").map(|a| (a.into(): &str).to_lowercase());
+        if default.is_some() && is_vec {
+            panic!("stomp-macros: chained subcommand field '{}' can't have a default", field.ident.as_ref().unwrap().as_ref());
+        }
+
         Subcommand {
             ident: field.ident.as_ref().unwrap(),
             ty: ty,
             is_optional: is_optional,
+            is_vec: is_vec,
+            default: default,
+        }
+    }
+}
+
+impl<'a> From<(&'a syn::Field, bool)> for Mode<'a> {
+    fn from((field, is_sequence): (&'a syn::Field, bool)) -> Mode<'a> {
+        let (is_optional, ty);
+        match field.ty {
+            syn::Ty::Path(None, ref path) => {
+                is_optional = path.segments[0].ident == "Option";
+                let is_vec = path.segments[0].ident == "Vec";
+                if is_sequence != is_vec {
+                    panic!("stomp-macros: field '{}' must be a Vec if and only if it's a sequence", field.ident.as_ref().unwrap().as_ref());
+                }
+                if is_optional || is_vec {
+                    if let syn::PathParameters::AngleBracketed(ref params) = path.segments[0].parameters {
+                        ty = &params.types[0];
+                    } else {
+                        panic!();
+                    }
+                } else {
+                    ty = &field.ty;
+                }
+            }
+            _ => panic!("unsupported field type {:?}", field.ty),
+        };
+
+        Mode {
+            ident: field.ident.as_ref().unwrap(),
+            ty: ty,
+            is_optional: is_optional,
+            is_sequence: is_sequence,
         }
     }
 }
+
+impl<'a> From<(&'a syn::Field, &'a Attributes)> for Skip<'a> {
+    fn from((field, attrs): (&'a syn::Field, &'a Attributes)) -> Skip<'a> {
+        Skip {
+            ident: field.ident.as_ref().unwrap(),
+            default: attrs.get("default").map(|expr| {
+                let mut tokens = quote::Tokens::new();
+                tokens.append(expr.into());
+                tokens
+            }),
+        }
+    }
+}
+
+impl<'a> From<&'a syn::Field> for Unknown<'a> {
+    fn from(field: &'a syn::Field) -> Unknown<'a> {
+        let is_os_strings = match field.ty {
+            syn::Ty::Path(None, ref path) if path.segments[0].ident == "Vec" => {
+                match path.segments[0].parameters {
+                    syn::PathParameters::AngleBracketed(ref params) => match params.types[0] {
+                        syn::Ty::Path(None, ref path) => path.segments.last().unwrap().ident == "OsString",
+                        _ => false,
+                    },
+                    _ => false,
+                }
+            }
+            _ => false,
+        };
+        if !is_os_strings {
+            panic!("stomp-macros: unknown field '{}' must be a Vec<OsString>", field.ident.as_ref().unwrap().as_ref());
+        }
+
+        Unknown { ident: field.ident.as_ref().unwrap() }
+    }
+}
@@ -2,15 +2,18 @@
 #![feature(type_ascription)]
 
 extern crate proc_macro;
+extern crate regex;
 extern crate syn;
 #[macro_use]
 extern crate quote;
 
+mod app;
 mod attr;
 mod attrs;
 mod field;
 mod stomp_command;
 mod stomp_commands;
+mod stomp_mode;
 
 #[proc_macro_derive(StompCommand)]
 pub fn stomp_command(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
@@ -24,7 +27,20 @@ pub fn stomp_command(input: proc_macro::TokenStream) -> proc_macro::TokenStream
 
 #[proc_macro_derive(StompCommands)]
 pub fn stomp_commands(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let ast = syn::parse_macro_input(&input.to_string()).unwrap();
-    let expanded = stomp_commands::expand(&ast);
+    let mut ast = syn::parse_macro_input(&input.to_string()).unwrap();
+    let (attrs, variant_attrs) = attrs::extract_attrs(&mut ast);
+    let expanded = stomp_commands::expand(&ast, &attrs, &variant_attrs);
+    attrs.check_used(ast.ident.as_ref(), None);
+    variant_attrs.check_used(ast.ident.as_ref());
+    quote!(#ast #expanded).to_string().parse().unwrap()
+}
+
+#[proc_macro_derive(StompMode)]
+pub fn stomp_mode(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let mut ast = syn::parse_macro_input(&input.to_string()).unwrap();
+    let (attrs, variant_attrs) = attrs::extract_attrs(&mut ast);
+    let expanded = stomp_mode::expand(&ast, &attrs, &variant_attrs);
+    attrs.check_used(ast.ident.as_ref(), None);
+    variant_attrs.check_used(ast.ident.as_ref());
     quote!(#ast #expanded).to_string().parse().unwrap()
 }
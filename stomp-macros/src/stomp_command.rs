@@ -1,74 +1,781 @@
+use std::collections::{ BTreeMap, BTreeSet };
+
+use regex;
 use syn;
 use quote;
 
-use attrs::{ Attributes, FieldAttributes };
-use field::{ Arg, Field, Subcommand };
+use app;
+use attrs::{ self, Attributes, FieldAttributes };
+use field::{ Arg, Field, Mode, Rename, Skip, Subcommand };
+
+struct Group<'a> {
+    name: &'a str,
+    required: bool,
+    multiple: bool,
+}
+
+/// Resolves references to other fields and groups into their clap names
+struct Names<'a> {
+    command: &'a str,
+    args: BTreeMap<&'a str, &'a str>,
+    groups: Vec<&'a str>,
+    /// The fields that give each field, group and mode a value, along with
+    /// whether they take one
+    members: BTreeMap<&'a str, Vec<(&'a str, bool)>>,
+    /// The fields, groups and modes that can get a value from the environment
+    env: BTreeSet<&'a str>,
+}
+
+impl<'a> Names<'a> {
+    fn new(ast: &'a syn::MacroInput, fields: &[Field<'a>], groups: &[Group<'a>]) -> Names<'a> {
+        let mut members = BTreeMap::new();
+        let mut env = BTreeSet::new();
+        for arg in fields.iter().filter_map(|field| field.arg()) {
+            let key = arg.ident.as_ref();
+            members.insert(key, vec![(key, arg.takes_value)]);
+            if let Some(group) = arg.group {
+                members.entry(group).or_insert_with(Vec::new).push((key, arg.takes_value));
+            }
+            if arg.env.is_some() {
+                env.insert(key);
+                env.extend(arg.group);
+            }
+        }
+        for mode in fields.iter().filter_map(|field| field.mode()) {
+            let key = mode.ident.as_ref();
+            members.insert(key, vec![(key, true)]);
+        }
+        Names {
+            members: members,
+            env: env,
+            command: ast.ident.as_ref(),
+            args: fields.iter()
+                .filter_map(|field| field.arg())
+                .map(|arg| (arg.ident.as_ref(), arg.name))
+                .collect(),
+            // Modes are a group named after their field
+            groups: groups.iter()
+                .map(|group| group.name)
+                .chain(fields.iter()
+                    .filter_map(|field| field.mode())
+                    .filter(|mode| !mode.is_sequence)
+                    .map(|mode| mode.ident.as_ref()))
+                .collect(),
+        }
+    }
+
+    fn resolve(&self, arg: &Arg, reference: &str) -> &'a str {
+        if let Some(&name) = self.args.get(reference) {
+            name
+        } else if let Some(&group) = self.groups.iter().find(|&&group| group == reference) {
+            group
+        } else {
+            panic!("stomp-macros: field '{}' of '{}' refers to unknown field '{}'", arg.ident.as_ref(), self.command, reference)
+        }
+    }
+
+    fn resolve_all(&self, arg: &Arg, references: &[String]) -> Vec<&'a str> {
+        references.iter().map(|reference| self.resolve(arg, reference)).collect()
+    }
+
+    fn has_env(&self, reference: &str) -> bool {
+        self.env.contains(reference)
+    }
+
+    /// Whether any field giving the field, group or mode was given anywhere,
+    /// as recorded in `ctx` while parsing.
+    fn given(&self, reference: &str, ctx: &syn::Ident) -> quote::Tokens {
+        let given = self.members.get(reference).map_or(&[][..], |members| &members[..]).iter()
+            .map(|&(key, takes_value)| quote! { #ctx.is_given(#key, #takes_value) });
+        quote! { (false #(|| #given)*) }
+    }
+}
 
-fn expand_arg(arg: &Arg) -> quote::Tokens {
+/// Splits a range like `1..=65535`, `0..10` or `1..` into the checks on
+/// `value` for each end.
+fn expand_range(arg: &Arg, range: &str) -> quote::Tokens {
+    let (lower, upper, inclusive) = match range.find("..") {
+        Some(i) if range[i..].starts_with("..=") => (&range[..i], &range[(i + 3)..], true),
+        Some(i) => (&range[..i], &range[(i + 2)..], false),
+        None => panic!("stomp-macros: invalid range '{}' on field '{}'", range, arg.ident.as_ref()),
+    };
+    let bound = |bound: &str| {
+        let mut tokens = quote::Tokens::new();
+        tokens.append(bound.trim());
+        tokens
+    };
+    let lower = if lower.trim().is_empty() {
+        None
+    } else {
+        let lower = bound(lower);
+        Some(quote! { if value < #lower { return Err(out_of_range); } })
+    };
+    let upper = if upper.trim().is_empty() {
+        None
+    } else if inclusive {
+        let upper = bound(upper);
+        Some(quote! { if value > #upper { return Err(out_of_range); } })
+    } else {
+        let upper = bound(upper);
+        Some(quote! { if value >= #upper { return Err(out_of_range); } })
+    };
+    let name = arg.name;
+    quote! {
+        let out_of_range = format!("value {:?} for argument '{}' is not in the range {}", s, #name, #range);
+        #lower
+        #upper
+    }
+}
+
+/// A closure checking a single value for the argument, by parsing it and
+/// checking any constraints on it.
+fn expand_check(arg: &Arg) -> quote::Tokens {
     let name = arg.name;
     let ty = arg.ty;
-    let short = arg.short.as_ref().map(|s| quote! { .short(#s) });
-    let long = arg.long.map(|s| quote! { .long(#s) });
-    let value_name = arg.value_name.map(|s| quote! { .value_name(#s) });
+    let has_constraints = arg.range.is_some() || arg.pattern.is_some() || arg.min_len.is_some() || arg.max_len.is_some() || arg.validator.is_some();
+    if !has_constraints {
+        return quote! {
+            |s: String| {
+                <#ty as ::std::str::FromStr>::from_str(&s)
+                    .map(|_| ())
+                    .map_err(|e| format!("failed to parse value {:?} for argument '{}': {}", s, #name, e))
+            }
+        };
+    }
+
+    let range = arg.range.map(|range| expand_range(arg, range));
+    let pattern = arg.pattern.map(|pattern| {
+        if let Err(e) = regex::Regex::new(pattern) {
+            panic!("stomp-macros: invalid pattern on field '{}': {}", arg.ident.as_ref(), e);
+        }
+        quote! {
+            if !::stomp::is_match(#pattern, &s) {
+                return Err(format!("value {:?} for argument '{}' doesn't match the pattern {}", s, #name, #pattern));
+            }
+        }
+    });
+    let min_len = arg.min_len.map(|min_len| quote! {
+        if s.chars().count() < #min_len as usize {
+            return Err(format!("value {:?} for argument '{}' is shorter than {} characters", s, #name, #min_len));
+        }
+    });
+    let max_len = arg.max_len.map(|max_len| quote! {
+        if s.chars().count() > #max_len as usize {
+            return Err(format!("value {:?} for argument '{}' is longer than {} characters", s, #name, #max_len));
+        }
+    });
+    let validator = arg.validator.map(|validator| {
+        let validator = syn::parse_path(validator).expect("stomp-macros: invalid validator path");
+        quote! {
+            if let Err(e) = #validator(&value) {
+                return Err(format!("invalid value {:?} for argument '{}': {}", s, #name, e));
+            }
+        }
+    });
+
+    quote! {
+        |s: String| -> ::std::result::Result<(), String> {
+            let value = match <#ty as ::std::str::FromStr>::from_str(&s) {
+                Ok(value) => value,
+                Err(e) => return Err(format!("failed to parse value {:?} for argument '{}': {}", s, #name, e)),
+            };
+            #min_len
+            #max_len
+            #pattern
+            #range
+            #validator
+            let _ = value;
+            Ok(())
+        }
+    }
+}
+
+fn expand_validator(arg: &Arg) -> Option<quote::Tokens> {
+    // Raw values are passed through verbatim
+    if arg.takes_value && !arg.is_raw {
+        let check = expand_check(arg);
+        Some(quote! { .validator(#check) })
+    } else {
+        None
+    }
+}
+
+fn expand_help(command: &str, arg: &Arg) -> quote::Tokens {
+    let ty = arg.ty;
+    let mut suffix = String::new();
+    if let Some(ref var) = arg.env {
+        suffix = suffix + " [env: " + var + "]";
+    }
+    if let Some(range) = arg.range {
+        suffix = suffix + " [range: " + range + "]";
+    }
+    if let Some(pattern) = arg.pattern {
+        suffix = suffix + " [pattern: " + pattern + "]";
+    }
+    let short = arg.summary.trim().to_string() + &suffix;
+    let long = (arg.summary.to_string() + "\n\n" + arg.docs).trim().to_string() + &suffix;
+    let help = attrs::expand_help(&short, &long);
+    // Typed defaults can only be displayed at runtime, once per field
+    let site = format!("{}.{}", command, arg.ident.as_ref());
+    match arg.default {
+        Some(ref default) => quote! {
+            ::stomp::cached(concat!(module_path!(), "::", #site), || {
+                let default: #ty = #default;
+                format!("{} [default: {}]", #help, ::stomp::show_default(&default))
+            })
+        },
+        None => help,
+    }
+}
+
+/// Sets the short name, or the one given when the command is built. clap
+/// takes an empty short as none.
+fn expand_short(arg: &Arg) -> Option<quote::Tokens> {
+    let name = arg.name;
+    if arg.wants_short {
+        Some(quote! {
+            .short(::stomp::auto_short(&Self::__stomp_shorts(), #name)
+                .map(|short| short.to_string())
+                .unwrap_or_else(String::new))
+        })
+    } else {
+        arg.short.as_ref().map(|s| quote! { .short(#s) })
+    }
+}
+
+fn expand_arg(arg: &Arg, names: &Names) -> quote::Tokens {
+    let name = arg.name;
+    let short = expand_short(arg);
+    let long = arg.long.as_ref().map(|s| quote! { .long(#s) });
+    let value_name = arg.value_name.as_ref().map(|s| quote! { .value_name(#s) });
     let takes_value = arg.takes_value;
     let index = arg.index.map(|i| quote! { .index(#i) });
-    let docs = (arg.summary.to_string() + "\n\n" + arg.docs).trim().to_string();
+    let help = expand_help(names.command, arg);
+    let display_order = arg.display_order;
     let multiple = arg.multiple;
     let default_value = arg.default_value.map(|d| quote! { .default_value(#d) });
     let min_values = arg.min_values.map(|m| quote! { .min_values(#m) });
     let max_values = arg.max_values.map(|m| quote! { .max_values(#m) });
-    let required = arg.required;
-    let validator = if arg.takes_value {
-        Some(quote! {
-            .validator(|s| {
-                <#ty as ::std::str::FromStr>::from_str(&s)
-                    .map(|_| ())
-                    .map_err(|e| format!("failed to parse value {:?} for argument '{}': {}", s, #name, e))
-            })
-        })
+    // Values from the environment or the context's layers are checked for
+    // when parsing instead
+    let required = if arg.required && arg.env.is_none() {
+        quote! { !::stomp::relax_required() }
+    } else {
+        quote! { false }
+    };
+    let validator = expand_validator(arg);
+    let allow_hyphen_values = if arg.is_raw {
+        Some(quote! { .allow_hyphen_values(true) })
+    } else {
+        None
+    };
+    // Arguments under a heading are shown in after_help instead
+    let hidden = arg.hidden || arg.help_heading.is_some();
+    // Deprecated aliases are hidden like any other
+    let aliases = if arg.aliases.is_empty() && arg.deprecated_aliases.is_empty() {
+        None
     } else {
+        let aliases = arg.aliases.iter().chain(&arg.deprecated_aliases);
+        Some(quote! { .aliases(&[#(#aliases),*]) })
+    };
+    let visible_aliases = if arg.visible_aliases.is_empty() {
         None
+    } else {
+        let ref visible_aliases = arg.visible_aliases;
+        Some(quote! { .visible_aliases(&[#(#visible_aliases),*]) })
     };
 
-    quote! {
+    let conflicts_with = if arg.conflicts_with.is_empty() {
+        None
+    } else {
+        let conflicts_with = names.resolve_all(arg, &arg.conflicts_with);
+        Some(quote! { .conflicts_with_all(&[#(#conflicts_with),*]) })
+    };
+    let requires = if arg.requires.is_empty() {
+        None
+    } else {
+        let requires = names.resolve_all(arg, &arg.requires);
+        Some(quote! { .requires_all(&[#(#requires),*]) })
+    };
+    let required_unless = if arg.required_unless.is_empty() {
+        None
+    } else {
+        let required_unless = names.resolve_all(arg, &arg.required_unless);
+        Some(quote! { .required_unless_one(&[#(#required_unless),*]) })
+    };
+    let requires_if: Vec<_> = arg.requires_if.iter().map(|&(ref field, value)| {
+        let field = names.resolve(arg, field);
+        quote! { .requires_if(#value, #field) }
+    }).collect();
+
+    let arg_tokens = quote! {
         ::clap::Arg::with_name(#name)
             #short
             #long
             #value_name
             #index
-            .help(#docs)
+            #aliases
+            #visible_aliases
+            .help(#help)
+            .display_order(#display_order)
+            .hidden(#hidden)
             .takes_value(#takes_value)
             .multiple(#multiple)
             #default_value
             #min_values
             #max_values
             .required(#required)
+            #conflicts_with
+            #validator
+            #allow_hyphen_values
+    };
+    if requires.is_none() && required_unless.is_none() && requires_if.is_empty() {
+        return arg_tokens;
+    }
+
+    // clap would count values from the environment or the context's layers
+    // as missing, so these are left to `expand_constraints` when there can be
+    // any
+    let mut references = arg.requires.iter()
+        .chain(&arg.required_unless)
+        .chain(arg.requires_if.iter().map(|&(ref field, _)| field));
+    let strict = if names.has_env(arg.ident.as_ref()) || references.any(|reference| names.has_env(reference)) {
+        quote! { false }
+    } else {
+        quote! { !::stomp::relax_required() }
+    };
+    quote! {{
+        let arg = #arg_tokens;
+        if #strict {
+            arg
+                #requires
+                #required_unless
+                #(#requires_if)*
+        } else {
+            arg
+        }
+    }}
+}
+
+/// Checks the constraints between arguments again once every field is
+/// parsed, as clap only sees the command line and these should hold for
+/// values from the environment and the context's layers too.
+fn expand_constraints(fields: &[Field], groups: &[Group], names: &Names, matches: &syn::Ident, ctx: &syn::Ident) -> Vec<quote::Tokens> {
+    let mut checks = Vec::new();
+    for arg in fields.iter().filter_map(|field| field.arg()) {
+        let name = arg.name;
+        let key = arg.ident.as_ref();
+        let given = names.given(key, ctx);
+        if !arg.required_unless.is_empty() {
+            let others = arg.required_unless.iter().map(|reference| names.given(reference, ctx));
+            checks.push(quote! {
+                if !#given #(&& !#others)* {
+                    return Err(::stomp::missing_value(#name, #matches.usage()));
+                }
+            });
+        }
+        for reference in &arg.requires {
+            let other = names.resolve(arg, reference);
+            let other_given = names.given(reference, ctx);
+            let message = format!("The argument '{}' requires '{}'", name, other);
+            checks.push(quote! {
+                if #given && !#other_given {
+                    return Err(::stomp::unsatisfied(#message, ::clap::ErrorKind::MissingRequiredArgument, #matches.usage()));
+                }
+            });
+        }
+        for &(ref reference, value) in &arg.requires_if {
+            let other = names.resolve(arg, reference);
+            let other_given = names.given(reference, ctx);
+            let message = format!("The argument '{}' with '{}' requires '{}'", name, value, other);
+            checks.push(quote! {
+                if #ctx.is_given_as(#key, #value) && !#other_given {
+                    return Err(::stomp::unsatisfied(#message, ::clap::ErrorKind::MissingRequiredArgument, #matches.usage()));
+                }
+            });
+        }
+        for reference in &arg.conflicts_with {
+            let other = names.resolve(arg, reference);
+            let other_given = names.given(reference, ctx);
+            let message = format!("The argument '{}' cannot be used with '{}'", name, other);
+            checks.push(quote! {
+                if #given && #other_given {
+                    return Err(::stomp::unsatisfied(#message, ::clap::ErrorKind::ArgumentConflict, #matches.usage()));
+                }
+            });
+        }
+    }
+    for group in groups {
+        let name = group.name;
+        let members = names.members.get(name).map_or(&[][..], |members| &members[..]);
+        let given: Vec<_> = members.iter().map(|&(key, takes_value)| quote! { #ctx.is_given(#key, #takes_value) }).collect();
+        if group.required {
+            let given = given.iter();
+            checks.push(quote! {
+                if !(false #(|| #given)*) {
+                    return Err(::stomp::missing_value(#name, #matches.usage()));
+                }
+            });
+        }
+        if !group.multiple {
+            let given = given.iter();
+            let message = format!("Only one argument of '{}' can be used", name);
+            checks.push(quote! {
+                if [#(#given),*].iter().filter(|&&given| given).count() > 1 {
+                    return Err(::stomp::unsatisfied(#message, ::clap::ErrorKind::ArgumentConflict, #matches.usage()));
+                }
+            });
+        }
+    }
+    checks
+}
+
+fn expand_args(fields: &[Field], names: &Names) -> quote::Tokens {
+    let args = fields.iter().filter_map(|field| field.arg()).map(|arg| expand_arg(arg, names));
+    let modes: Vec<_> = fields.iter()
+        .filter_map(|field| field.mode())
+        .map(|mode| {
+            let ty = mode.ty;
+            if mode.is_sequence {
+                quote! { args.extend(<#ty as ::stomp::StompMode>::repeated_args()); }
+            } else {
+                quote! { args.extend(<#ty as ::stomp::StompMode>::args()); }
+            }
+        })
+        .collect();
+
+    if modes.is_empty() {
+        quote! { vec![#(#args),*] }
+    } else {
+        quote! {{
+            let mut args = vec![#(#args),*];
+            #(#modes)*
+            args
+        }}
+    }
+}
+
+/// A cut down argument for `early_command`, without any requirements or
+/// relationships to arguments that aren't there.
+fn expand_early_arg(arg: &Arg) -> quote::Tokens {
+    let name = arg.name;
+    let short = expand_short(arg);
+    let long = arg.long.as_ref().map(|s| quote! { .long(#s) });
+    let aliases = arg.aliases.iter().chain(&arg.visible_aliases).chain(&arg.deprecated_aliases);
+    let takes_value = arg.takes_value;
+    let multiple = arg.multiple;
+    let default_value = arg.default_value.map(|d| quote! { .default_value(#d) });
+    let validator = expand_validator(arg);
+
+    quote! {
+        ::clap::Arg::with_name(#name)
+            #short
+            #long
+            .aliases(&[#(#aliases),*])
+            .takes_value(#takes_value)
+            .multiple(#multiple)
+            #default_value
             #validator
     }
 }
 
-fn expand_args<'a, 'b: 'a, I>(args: I) -> quote::Tokens where I: Iterator<Item=&'a Arg<'b>> {
-    let args = args.map(expand_arg);
-    quote! { .args(&[#(#args),*]) }
+/// The `<Command>Early` struct with a field for each `#[stomp(early)]`
+/// argument, those that are required become optional as nothing is required
+/// early. Values missing from the command line fall back to the environment
+/// and the context's layers as in the full parse. Commands without early
+/// arguments use `()` rather than an empty struct.
+fn expand_early(ast: &syn::MacroInput, fields: &[Field], matches: &syn::Ident, ctx: &syn::Ident) -> (quote::Tokens, Option<quote::Tokens>, quote::Tokens) {
+    if !fields.iter().filter_map(|field| field.arg()).any(|arg| arg.is_early) {
+        return (quote! { () }, None, quote! { Ok(()) });
+    }
+
+    let ident = syn::Ident::from(ast.ident.as_ref().to_owned() + "Early");
+    let vis = &ast.vis;
+    let doc = format!("The early arguments of `{}`, parsed before the full command.", ast.ident.as_ref());
+
+    let mut decls = Vec::new();
+    let mut values = Vec::new();
+    for arg in fields.iter().filter_map(|field| field.arg()).filter(|arg| arg.is_early) {
+        let field = arg.ident;
+        let name = arg.name;
+        let ty = arg.ty;
+        let (decl, from_matches, from_fallback) = if !arg.takes_value {
+            if arg.is_counter {
+                (quote! { #ty }, quote! { #matches.occurrences_of(#name) }, quote! { fallback.single::<#ty>(#name)? })
+            } else {
+                (quote! { #ty }, quote! { #matches.is_present(#name) }, quote! { fallback.flag(#name)? })
+            }
+        } else if arg.multiple {
+            (quote! { ::std::vec::Vec<#ty> }, quote! {
+                #matches
+                    .values_of(#name)
+                    .map(|vs| vs.map(|v| v.parse().unwrap()).collect())
+                    .unwrap_or_else(|| Vec::new())
+            }, quote! { fallback.all::<#ty>(#name)? })
+        } else if let Some(ref default) = arg.default {
+            (quote! { #ty }, quote! {
+                #matches
+                    .value_of(#name)
+                    .map(|v| v.parse().unwrap())
+                    .unwrap_or_else(|| #default)
+            }, quote! { fallback.single::<#ty>(#name)? })
+        } else if arg.default_value.is_some() {
+            (quote! { #ty }, quote! { #matches.value_of(#name).unwrap().parse().unwrap() }, quote! { fallback.single::<#ty>(#name)? })
+        } else {
+            (quote! { ::std::option::Option<#ty> }, quote! { #matches.value_of(#name).map(|v| v.parse().unwrap()) }, quote! { Some(fallback.single::<#ty>(#name)?) })
+        };
+        let key = arg.ident.as_ref();
+        let env = match arg.env {
+            Some(ref var) => quote! { Some(#var) },
+            None => quote! { None },
+        };
+        let check_fallback = if arg.takes_value {
+            let check = expand_check(arg);
+            Some(quote! { fallback.check(#check)?; })
+        } else {
+            None
+        };
+        let value = if arg.is_raw {
+            from_matches
+        } else {
+            quote! {
+                if #matches.occurrences_of(#name) > 0 {
+                    #from_matches
+                } else if let Some(fallback) = #ctx.fallback(#key, #env) {
+                    #check_fallback
+                    #from_fallback
+                } else {
+                    #from_matches
+                }
+            }
+        };
+        decls.push(quote! { pub #field: #decl });
+        values.push(quote! { #field: #value });
+    }
+
+    let tokens = quote! {
+        #[doc = #doc]
+        #vis struct #ident {
+            #(#decls),*
+        }
+    };
+    let parse = quote! {
+        Ok(#ident {
+            #(#values),*
+        })
+    };
+    (quote! { #ident }, Some(tokens), parse)
+}
+
+fn expand_early_command(ast: &syn::MacroInput, attrs: &Attributes, fields: &[Field]) -> quote::Tokens {
+    let name = command_name(ast, attrs);
+    let args = fields.iter()
+        .filter_map(|field| field.arg())
+        .filter(|arg| arg.is_early)
+        .map(expand_early_arg);
+    quote! {
+        ::clap::App::new(#name)
+            .args(&[#(#args),*])
+    }
+}
+
+fn expand_spec(arg: &Arg) -> quote::Tokens {
+    let name = arg.name;
+    let short = match arg.short {
+        Some(ref short) => {
+            let short = short.chars().next().unwrap();
+            quote! { Some(#short) }
+        }
+        None if arg.wants_short => quote! { ::stomp::auto_short(&Self::__stomp_shorts(), #name) },
+        None => quote! { None },
+    };
+    let long = match arg.long {
+        Some(ref long) => quote! { Some(#long) },
+        None => quote! { None },
+    };
+    let aliases = arg.aliases.iter().chain(&arg.visible_aliases).chain(&arg.deprecated_aliases);
+    let takes_value = arg.takes_value;
+    let early = arg.is_early;
+    let index = match arg.index {
+        Some(index) => quote! { Some(#index) },
+        None => quote! { None },
+    };
+    quote! {
+        ::stomp::ArgSpec {
+            name: #name,
+            short: #short,
+            long: #long,
+            aliases: vec![#(#aliases),*],
+            takes_value: #takes_value,
+            early: #early,
+            index: #index,
+        }
+    }
+}
+
+fn extract_groups<'a>(ast: &syn::MacroInput, attrs: &'a Attributes, fields: &[Field]) -> Vec<Group<'a>> {
+    let groups: Vec<_> = attrs.get("group")
+        .map(|a| {
+            a.nested().iter()
+                .map(|group| Group {
+                    name: group.get("name").map(|a| a.into())
+                        .unwrap_or_else(|| panic!("stomp-macros: group on '{}' is missing a name", ast.ident.as_ref())),
+                    required: group.get_bool("required"),
+                    multiple: group.get_bool("multiple"),
+                })
+                .collect()
+        })
+        .unwrap_or_else(Vec::new);
+
+    for arg in fields.iter().filter_map(|field| field.arg()) {
+        if let Some(name) = arg.group {
+            if !groups.iter().any(|group| group.name == name) {
+                panic!("stomp-macros: field '{}' of '{}' is in undeclared group '{}'", arg.ident.as_ref(), ast.ident.as_ref(), name);
+            }
+        }
+    }
+
+    groups
+}
+
+fn expand_mode_group(mode: &Mode) -> quote::Tokens {
+    let name = mode.ident.as_ref();
+    let ty = mode.ty;
+    let required = if mode.is_optional {
+        quote! { false }
+    } else {
+        quote! { <#ty as ::stomp::StompMode>::default_mode().is_none() && !::stomp::explaining() }
+    };
+    quote! {
+        ::clap::ArgGroup::with_name(#name)
+            .args(&<#ty as ::stomp::StompMode>::names())
+            .required(#required)
+    }
+}
+
+fn expand_groups(ast: &syn::MacroInput, groups: &[Group], fields: &[Field]) -> quote::Tokens {
+    let modes: Vec<_> = fields.iter().filter_map(|field| field.mode()).filter(|mode| !mode.is_sequence).collect();
+
+    let modes = modes.into_iter().map(expand_mode_group);
+    let groups = groups.iter().map(|group| {
+        let name = group.name;
+        let required = group.required;
+        let multiple = group.multiple;
+        let args: Vec<_> = fields.iter()
+            .filter_map(|field| field.arg())
+            .filter(|arg| arg.group == Some(name))
+            .map(|arg| arg.name)
+            .collect();
+        if args.is_empty() {
+            panic!("stomp-macros: group '{}' on '{}' has no fields", name, ast.ident.as_ref());
+        }
+        // As with required arguments, a value from elsewhere is checked for
+        // in `expand_constraints` instead
+        let has_env = fields.iter()
+            .filter_map(|field| field.arg())
+            .any(|arg| arg.group == Some(name) && arg.env.is_some());
+        let required = if required && !has_env {
+            quote! { !::stomp::relax_required() }
+        } else {
+            quote! { false }
+        };
+        quote! {
+            ::clap::ArgGroup::with_name(#name)
+                .args(&[#(#args),*])
+                .required(#required)
+                .multiple(#multiple)
+        }
+    });
+
+    quote! { vec![#(#groups,)* #(#modes),*] }
 }
 
 fn expand_subcommand(subcommand: &Subcommand) -> quote::Tokens {
     let ty = subcommand.ty;
-    let required = if subcommand.is_optional {
+
+    if subcommand.is_vec {
+        return quote! {
+            .subcommands(::stomp::chain(<#ty as ::stomp::StompCommands>::commands()))
+            .setting(::clap::AppSettings::SubcommandsNegateReqs)
+        };
+    }
+
+    let required = if subcommand.is_optional || subcommand.default.is_some() {
         None
     } else {
         Some(quote! { .setting(::clap::AppSettings::SubcommandRequiredElseHelp) })
     };
+    // The default's groups keep it checked like any other subcommand
+    let default_args = subcommand.default.as_ref().map(|default| quote! {
+        .args(&<#ty as ::stomp::StompCommands>::args(#default))
+        .groups(&<#ty as ::stomp::StompCommands>::groups(#default))
+        .setting(::clap::AppSettings::SubcommandsNegateReqs)
+    });
 
     quote! {
         .subcommands(<#ty as ::stomp::StompCommands>::commands())
         #required
+        #default_args
+    }
+}
+
+fn command_name(ast: &syn::MacroInput, attrs: &Attributes) -> syn::Lit {
+    attrs.get("name").map(|a| a.into())
+        .unwrap_or_else(|| syn::Lit::from(ast.ident.as_ref().to_lowercase()))
+}
+
+/// How the argument is shown in help, e.g. `-c, --config <FILE>`
+fn usage(arg: &Arg) -> String {
+    let value_name = arg.value_name.clone().unwrap_or_else(|| arg.name.to_owned());
+    let mut usage = if arg.is_positional {
+        format!("<{}>", value_name)
+    } else {
+        let mut names = Vec::new();
+        if let Some(ref short) = arg.short {
+            names.push(format!("-{}", short));
+        }
+        if let Some(ref long) = arg.long {
+            names.push(format!("--{}", long));
+        }
+        let mut usage = names.join(", ");
+        if arg.takes_value {
+            usage = usage + " <" + &value_name + ">";
+        }
+        usage
+    };
+    if arg.multiple {
+        usage.push_str("...");
+    }
+    usage
+}
+
+/// The sections of help for arguments with a `help_heading`, clap 2 doesn't
+/// support headings so they're rendered before the command's after_help.
+fn expand_headings(ast: &syn::MacroInput, fields: &[Field]) -> Option<quote::Tokens> {
+    let entries: Vec<_> = fields.iter()
+        .filter_map(|field| field.arg())
+        .filter(|arg| !arg.hidden)
+        .filter_map(|arg| arg.help_heading.map(|heading| {
+            let name = arg.name;
+            let usage = usage(arg);
+            let usage = if arg.wants_short {
+                quote! { &*::stomp::usage_with_short(#usage, ::stomp::auto_short(&Self::__stomp_shorts(), #name)) }
+            } else {
+                quote! { #usage }
+            };
+            let help = expand_help(ast.ident.as_ref(), arg);
+            quote! { (#heading, #usage, #help) }
+        }))
+        .collect();
+    if entries.is_empty() {
+        None
+    } else {
+        Some(quote! { ::stomp::render_headings(&[#(#entries),*]) })
     }
 }
 
 fn expand_command(ast: &syn::MacroInput, attrs: &Attributes, fields: &[Field]) -> quote::Tokens {
-    let name = attrs.get("name").map(|a| a.into())
-            .unwrap_or_else(|| syn::Lit::from(ast.ident.as_ref().to_lowercase()));
+    let name = command_name(ast, attrs);
 
     let version = if attrs.get_bool("crate_version") {
         Some(quote! { .version(crate_version!()) })
@@ -82,37 +789,80 @@ fn expand_command(ast: &syn::MacroInput, attrs: &Attributes, fields: &[Field]) -
         attrs.get("author").map(|a| quote! { .author(#a) })
     };
 
-    let args = expand_args(fields.iter().filter_map(|field| field.arg()));
-    let subcommand = fields.iter()
-        .filter_map(|field| field.subcommand())
-        .find(|_| true)
-        .map(expand_subcommand);
-
-    let ref summary = attrs.summary;
-    let ref docs = attrs.docs;
-    let alias = attrs.get("alias").map(|a| quote! { .alias(#a) });
-    let global_settings = attrs.get("global_settings").map(|a| {
-        let settings = a.values().into_iter().map(syn::Ident::from);
-        quote! { .global_settings(&[#(::clap::AppSettings::#settings),*]) }
-    });
+    let subcommands: Vec<_> = fields.iter().filter_map(|field| field.subcommand()).collect();
+    if subcommands.len() > 1 {
+        panic!("#[derive(StompCommand)] only supports a single subcommand field, found {} on '{}'", subcommands.len(), ast.ident.as_ref());
+    }
+    let subcommand = subcommands.first().map(|subcommand| expand_subcommand(subcommand));
+
+    let about = match attrs.get("about") {
+        Some(about) => quote! { #about },
+        None => {
+            let ref summary = attrs.summary;
+            quote! { #summary }
+        }
+    };
+    // The docs are only shown for --help
+    let docs = match attrs.get("after_help") {
+        Some(after_help) => quote! { #after_help },
+        None => attrs::expand_help("", &attrs.docs),
+    };
+    let settings = app::expand_settings(attrs);
+
+    let raw: Vec<_> = fields.iter().filter_map(|field| field.arg()).filter(|arg| arg.is_raw).collect();
+    if raw.len() > 1 {
+        panic!("#[derive(StompCommand)] only supports a single raw field, found {} on '{}'", raw.len(), ast.ident.as_ref());
+    }
+    let trailing = raw.first().map(|_| quote! { .setting(::clap::AppSettings::TrailingVarArg) });
+
+    let after_help = match expand_headings(ast, fields) {
+        Some(headings) => quote! { ::stomp::intern(#headings + #docs) },
+        None => docs,
+    };
+
+    let explain_config = if attrs.get_bool("explain_config") {
+        Some(quote! { .arg(::stomp::explain_config()) })
+    } else {
+        None
+    };
 
     quote! {
         ::clap::App::new(#name)
             #version
             #author
-            #args
+            .args(&<Self as ::stomp::StompCommand>::args())
+            #explain_config
+            #trailing
+            .groups(&<Self as ::stomp::StompCommand>::groups())
             #subcommand
-            .about(#summary)
-            .after_help(#docs)
-            #alias
-            #global_settings
+            .about(#about)
+            .after_help(#after_help)
+            #settings
     }
 }
 
-fn expand_parse_arg(arg: &Arg, matches: &syn::Ident) -> quote::Tokens {
+fn expand_parse_arg(arg: &Arg, matches: &syn::Ident, ctx: &syn::Ident) -> quote::Tokens {
     let ident = arg.ident;
     let name = arg.name;
-    let value = if arg.is_counter {
+    let ty = arg.ty;
+    let from_matches = if arg.is_raw && arg.is_os_string {
+        quote! {
+            #matches
+                .values_of_os(#name)
+                .map(|vs| vs.map(|v| v.to_owned()).collect())
+                .unwrap_or_else(|| Vec::new())
+        }
+    } else if arg.is_raw {
+        // clap's values_of would panic on arguments that aren't unicode
+        quote! {
+            match #matches.values_of_os(#name) {
+                Some(values) => values
+                    .map(|v| v.to_str().map(|v| v.to_owned()).ok_or_else(|| ::stomp::invalid_utf8(#name, v)))
+                    .collect::<::clap::Result<_>>()?,
+                None => Vec::new(),
+            }
+        }
+    } else if arg.is_counter {
         quote! { #matches.occurrences_of(#name) }
     } else {
         if arg.takes_value {
@@ -143,57 +893,636 @@ fn expand_parse_arg(arg: &Arg, matches: &syn::Ident) -> quote::Tokens {
         }
     };
 
+    // Precedence is command line, then environment, then the context's
+    // layers, then default value
+    let key = ident.as_ref();
+    let env = match arg.env {
+        Some(ref var) => quote! { Some(#var) },
+        None => quote! { None },
+    };
+    // clap's validator doesn't see values from elsewhere, so check them too
+    let check_fallback = if arg.takes_value {
+        let check = expand_check(arg);
+        Some(quote! { fallback.check(#check)?; })
+    } else {
+        None
+    };
+    let from_fallback = if arg.multiple && !arg.is_counter {
+        quote! { fallback.all::<#ty>(#name)? }
+    } else if !arg.takes_value && !arg.is_counter {
+        quote! { fallback.flag(#name)? }
+    } else if arg.is_optional {
+        quote! { Some(fallback.single::<#ty>(#name)?) }
+    } else {
+        quote! { fallback.single::<#ty>(#name)? }
+    };
+    // clap may have left checking required arguments to us
+    let from_default = if arg.required && arg.takes_value && arg.multiple {
+        quote! {
+            match #matches.values_of(#name) {
+                Some(values) => values.map(|v| v.parse().unwrap()).collect(),
+                None => return Err(::stomp::missing_value(#name, #matches.usage())),
+            }
+        }
+    } else if arg.required && arg.takes_value {
+        quote! {
+            match #matches.value_of(#name) {
+                Some(value) => value.parse().unwrap(),
+                None => return Err(::stomp::missing_value(#name, #matches.usage())),
+            }
+        }
+    } else {
+        from_matches.clone()
+    };
+    let otherwise = match arg.default {
+        Some(ref default) => quote! {
+            let value: #ty = #default;
+            #ctx.record(#key, ::stomp::Source::Default, vec![::stomp::show_default(&value)]);
+            value
+        },
+        None => quote! {
+            #ctx.record_matches(#key, #matches, #name);
+            #from_default
+        },
+    };
+    let deprecated = arg.deprecated.map(|message| {
+        let flag = match arg.long {
+            Some(ref long) => "--".to_owned() + long,
+            None => name.to_owned(),
+        };
+        quote! { ::stomp::deprecated(#flag, #message); }
+    });
+    // Only the raw arguments tell which name was used
+    let deprecated_aliases = arg.deprecated_aliases.iter().map(|alias| {
+        let flag = "--".to_owned() + alias;
+        let message = format!("use '--{}' instead", arg.long.as_ref().unwrap());
+        quote! { ::stomp::deprecated_alias(&<Self as ::stomp::StompCommand>::spec().args, #ctx, #flag, #message); }
+    });
+    // Raw values only come from the command line
+    if arg.is_raw {
+        return quote! {
+            #ident: {
+                #ctx.record_matches(#key, #matches, #name);
+                #from_matches
+            }
+        };
+    }
+
+    let value = quote! {
+        if #matches.occurrences_of(#name) > 0 {
+            #deprecated
+            #(#deprecated_aliases)*
+            #ctx.record_matches(#key, #matches, #name);
+            #from_matches
+        } else if let Some(fallback) = #ctx.fallback(#key, #env) {
+            #ctx.record_fallback(#key, &fallback);
+            #check_fallback
+            #from_fallback
+        } else {
+            #otherwise
+        }
+    };
+
     quote! {
         #ident: #value
     }
 }
 
-fn expand_parse_subcommand(cmd: &Subcommand, matches: &syn::Ident) -> quote::Tokens {
+fn expand_parse_subcommand(cmd: &Subcommand, collects_unknown: bool, app_name: &syn::Lit, matches: &syn::Ident, ctx: &syn::Ident) -> quote::Tokens {
     let ident = cmd.ident;
     let ty = cmd.ty;
 
-    let (default, wrapper);
-    if cmd.is_optional {
-        default = quote! { None };
-        wrapper = Some(quote! { Some });
-    } else {
-        default = quote! { unreachable!() };
-        wrapper = None;
+    if cmd.is_vec {
+        return quote! {
+            #ident: ::stomp::parse_chain::<#ty>(#app_name, #matches, #ctx)?
+        };
     }
 
+    let wrapper = if cmd.is_optional { Some(quote! { Some }) } else { None };
+    let default = if let Some(ref default) = cmd.default {
+        // The default subcommand's args were merged into ours, so it can be
+        // parsed straight from our matches
+        quote! { #wrapper(<#ty as ::stomp::StompCommands>::parse_with(#default, #matches, &#ctx.default_subcommand(#default))?) }
+    } else if cmd.is_optional {
+        quote! { None }
+    } else {
+        quote! { unreachable!() }
+    };
+
+    // Unknown options were split out in case the default subcommand takes
+    // them, but nothing will when another subcommand is given
+    let check_unknown = if cmd.default.is_some() && !collects_unknown {
+        Some(quote! { ::stomp::check_no_unknown(#ctx, #matches.usage())?; })
+    } else {
+        None
+    };
+
     quote! {
         #ident: match #matches.subcommand() {
-            (name, Some(matches)) => #wrapper(<#ty as ::stomp::StompCommands>::parse(name, matches)),
+            (name, Some(sub_matches)) => {
+                #check_unknown
+                #wrapper(<#ty as ::stomp::StompCommands>::parse_with(name, sub_matches, &#ctx.subcommand(name))?)
+            }
             (_, None) => #default,
         }
     }
 }
 
-fn expand_parse_field(field: &Field, matches: &syn::Ident) -> quote::Tokens {
+fn expand_parse_mode(mode: &Mode, matches: &syn::Ident, ctx: &syn::Ident) -> quote::Tokens {
+    let ident = mode.ident;
+    let key = ident.as_ref();
+    let ty = mode.ty;
+    if mode.is_sequence {
+        // clap doesn't tell us the order different arguments came in, so
+        // that's found from the raw arguments
+        return quote! {
+            #ident: {
+                let given: ::std::vec::Vec<String> = <#ty as ::stomp::StompMode>::names().into_iter()
+                    .filter(|name| #matches.is_present(name))
+                    .map(|name| name.to_owned())
+                    .collect();
+                let source = if given.is_empty() { ::stomp::Source::Unset } else { ::stomp::Source::CommandLine };
+                #ctx.record(#key, source, given);
+                ::stomp::parse_sequence::<#ty>(&<Self as ::stomp::StompCommand>::spec().args, #ctx, #matches.usage())?
+            }
+        };
+    }
+    // Optional modes don't use the default variant
+    let (default, unwrap) = if mode.is_optional {
+        (quote! { None }, None)
+    } else {
+        (quote! { <#ty as ::stomp::StompMode>::default_mode() }, Some(quote! { .unwrap() }))
+    };
+    // The values recorded are the names of the flags given
+    quote! {
+        #ident: match <#ty as ::stomp::StompMode>::parse(#matches) {
+            Some(mode) => {
+                let given = <#ty as ::stomp::StompMode>::names().into_iter()
+                    .filter(|name| #matches.is_present(name))
+                    .map(|name| name.to_owned())
+                    .collect();
+                #ctx.record(#key, ::stomp::Source::CommandLine, given);
+                Some(mode)
+            }
+            None => {
+                let default = #default;
+                let source = if default.is_some() { ::stomp::Source::Default } else { ::stomp::Source::Unset };
+                #ctx.record(#key, source, ::std::vec::Vec::new());
+                default
+            }
+        }#unwrap
+    }
+}
+
+/// Records where a field's value comes from without parsing it, for
+/// `--explain-config` which has to work even when required values are
+/// missing.
+fn expand_explain_field(field: &Field, matches: &syn::Ident, ctx: &syn::Ident) -> Option<quote::Tokens> {
     match *field {
-        Field::Arg(ref arg) => expand_parse_arg(arg, matches),
-        Field::Subcommand(ref cmd) => expand_parse_subcommand(cmd, matches),
+        Field::Arg(ref arg) => {
+            let name = arg.name;
+            let key = arg.ident.as_ref();
+            let ty = arg.ty;
+            if arg.is_raw {
+                return Some(quote! { #ctx.record_matches(#key, #matches, #name); });
+            }
+            let env = match arg.env {
+                Some(ref var) => quote! { Some(#var) },
+                None => quote! { None },
+            };
+            let otherwise = match arg.default {
+                Some(ref default) => quote! {
+                    let value: #ty = #default;
+                    #ctx.record(#key, ::stomp::Source::Default, vec![::stomp::show_default(&value)]);
+                },
+                None => quote! { #ctx.record_matches(#key, #matches, #name); },
+            };
+            Some(quote! {
+                if #matches.occurrences_of(#name) > 0 {
+                    #ctx.record_matches(#key, #matches, #name);
+                } else if let Some(fallback) = #ctx.fallback(#key, #env) {
+                    #ctx.record_fallback(#key, &fallback);
+                } else {
+                    #otherwise
+                }
+            })
+        }
+        Field::Mode(ref mode) => {
+            let key = mode.ident.as_ref();
+            let ty = mode.ty;
+            let has_default = if mode.is_optional || mode.is_sequence {
+                quote! { false }
+            } else {
+                quote! { <#ty as ::stomp::StompMode>::default_mode().is_some() }
+            };
+            Some(quote! {
+                let given: ::std::vec::Vec<String> = <#ty as ::stomp::StompMode>::names().into_iter()
+                    .filter(|name| #matches.is_present(name))
+                    .map(|name| name.to_owned())
+                    .collect();
+                let source = if !given.is_empty() {
+                    ::stomp::Source::CommandLine
+                } else if #has_default {
+                    ::stomp::Source::Default
+                } else {
+                    ::stomp::Source::Unset
+                };
+                #ctx.record(#key, source, given);
+            })
+        }
+        Field::Subcommand(ref cmd) => {
+            let ty = cmd.ty;
+            let default = cmd.default.as_ref().map(|default| quote! {
+                else {
+                    <#ty as ::stomp::StompCommands>::explain_with(#default, #matches, &#ctx.default_subcommand(#default));
+                }
+            });
+            Some(quote! {
+                if let (name, Some(matches)) = #matches.subcommand() {
+                    <#ty as ::stomp::StompCommands>::explain_with(name, matches, &#ctx.subcommand(name));
+                } #default
+            })
+        }
+        Field::Skip(_) | Field::Unknown(_) => None,
     }
 }
 
-fn expand_parse(ast: &syn::MacroInput, fields: &[Field], matches: &syn::Ident) -> quote::Tokens {
-    let name = &ast.ident;
-    let fields = fields.iter().map(|field| expand_parse_field(field, matches));
+/// The `<Command>Sources` struct with the source of each field, subcommand
+/// fields get the sources of everything within them.
+/// The `<Command>Sources` struct with the source of each field, only derived
+/// with `#[stomp(sources)]` so it doesn't take the name otherwise. Returns
+/// the type, its definition and how it's built from `sources`.
+fn expand_sources(ast: &syn::MacroInput, attrs: &Attributes, fields: &[Field]) -> (quote::Tokens, Option<quote::Tokens>, quote::Tokens) {
+    if !attrs.get_bool("sources") {
+        return (quote! { ::stomp::Sources }, None, quote! { sources.clone() });
+    }
+
+    let ident = syn::Ident::from(ast.ident.as_ref().to_owned() + "Sources");
+    let vis = &ast.vis;
+    let doc = format!("Where the value of each field of `{}` came from.", ast.ident.as_ref());
+
+    let mut decls = Vec::new();
+    let mut values = Vec::new();
+    for field in fields {
+        match *field {
+            Field::Arg(Arg { ident, .. }) | Field::Mode(Mode { ident, .. }) => {
+                let key = ident.as_ref();
+                decls.push(quote! { pub #ident: ::stomp::Source });
+                values.push(quote! {
+                    #ident: sources.get(&[], #key)
+                        .map(|entry| entry.source.clone())
+                        .unwrap_or(::stomp::Source::Unset)
+                });
+            }
+            Field::Subcommand(ref cmd) => {
+                let field = cmd.ident;
+                decls.push(quote! { pub #field: ::stomp::Sources });
+                values.push(quote! { #field: sources.nested(&[]) });
+            }
+            Field::Skip(_) | Field::Unknown(_) => (),
+        }
+    }
+
+    let tokens = quote! {
+        #[doc = #doc]
+        #[derive(Clone, Debug)]
+        #vis struct #ident {
+            #(#decls),*
+        }
+
+        impl #ident {
+            /// Finds the source of every field of the top level command from
+            /// all the sources recorded while parsing.
+            pub fn from_sources(sources: &::stomp::Sources) -> #ident {
+                #ident {
+                    #(#values),*
+                }
+            }
+        }
+    };
+    let build = quote! { #ident::from_sources(sources) };
+    (quote! { #ident }, Some(tokens), build)
+}
+
+fn expand_skip(skip: &Skip) -> quote::Tokens {
+    let ident = skip.ident;
+    match skip.default {
+        Some(ref default) => quote! { #ident: #default },
+        None => quote! { #ident: ::std::default::Default::default() },
+    }
+}
+
+fn expand_parse_field(field: &Field, collects_unknown: bool, app_name: &syn::Lit, matches: &syn::Ident, ctx: &syn::Ident) -> quote::Tokens {
+    match *field {
+        Field::Arg(ref arg) => expand_parse_arg(arg, matches, ctx),
+        Field::Subcommand(ref cmd) => expand_parse_subcommand(cmd, collects_unknown, app_name, matches, ctx),
+        Field::Mode(ref mode) => expand_parse_mode(mode, matches, ctx),
+        Field::Skip(ref skip) => expand_skip(skip),
+        Field::Unknown(ref unknown) => {
+            let ident = unknown.ident;
+            quote! { #ident: #ctx.take_unknown() }
+        }
+    }
+}
+
+/// Checks a field has a value when nothing is given on the command line, so
+/// the command can implement Default. Modes are defined elsewhere, so need a
+/// check in the generated code that they have a default variant.
+fn check_default_field(ast: &syn::MacroInput, field: &Field) -> Option<quote::Tokens> {
+    match *field {
+        Field::Arg(ref arg) => {
+            if arg.required && arg.takes_value && arg.default_value.is_none() {
+                panic!("stomp-macros: can't implement Default for '{}' as field '{}' is required", ast.ident.as_ref(), arg.ident.as_ref());
+            }
+            None
+        }
+        Field::Subcommand(ref cmd) => {
+            if !cmd.is_vec && !cmd.is_optional && cmd.default.is_none() {
+                panic!("stomp-macros: can't implement Default for '{}' as subcommand '{}' is required", ast.ident.as_ref(), cmd.ident.as_ref());
+            }
+            None
+        }
+        Field::Mode(ref mode) if !mode.is_sequence && !mode.is_optional => {
+            let ty = mode.ty;
+            Some(quote! { has_default_mode::<#ty>(); })
+        }
+        _ => None,
+    }
+}
+
+/// Checks the shorts only known once the command is built, from modes, the
+/// default subcommand and the variant containing the command, leave the help
+/// and version shorts alone.
+fn expand_check_reserved_shorts(attrs: &Attributes) -> quote::Tokens {
+    let help = attrs.get("help_short").map(|a| a.into()).unwrap_or('h');
+    let version = attrs.get("version_short").map(|a| a.into()).unwrap_or('V');
+    quote! { ::stomp::check_reserved_shorts(&<Self as ::stomp::StompCommand>::spec(), #help, #version); }
+}
+
+/// Checks the names of each mode's arguments aren't used by other fields,
+/// which the derive can't see.
+fn expand_check_mode_args(fields: &[Field]) -> Vec<quote::Tokens> {
+    fields.iter()
+        .filter_map(|field| field.mode())
+        .map(|mode| {
+            let field = mode.ident.as_ref();
+            let ty = mode.ty;
+            quote! { ::stomp::check_mode_args(&<Self as ::stomp::StompCommand>::spec(), #field, &<#ty as ::stomp::StompMode>::specs()); }
+        })
+        .collect()
+}
+
+/// Checks none of the chained subcommands take positional arguments.
+fn expand_check_chain(fields: &[Field]) -> Option<quote::Tokens> {
+    fields.iter()
+        .filter_map(|field| field.subcommand())
+        .find(|subcommand| subcommand.is_vec)
+        .map(|subcommand| {
+            let ty = subcommand.ty;
+            quote! { ::stomp::check_chain::<#ty>(); }
+        })
+}
+
+/// Checks the default subcommand is one of the variants that can be a
+/// default, and that its arguments don't clash with ours once merged.
+fn expand_check_default_subcommand(fields: &[Field]) -> Option<quote::Tokens> {
+    let subcommand = fields.iter().filter_map(|field| field.subcommand()).next();
+    subcommand.and_then(|subcommand| subcommand.default.as_ref().map(|default| {
+        let ty = subcommand.ty;
+        let marker = syn::Ident::from(format!("__stomp_default_{}", default));
+        let collects_unknown = fields.iter().any(|field| if let Field::Unknown(_) = *field { true } else { false });
+        quote! {
+            <#ty>::#marker();
+            ::stomp::check_default_args::<#ty>(&<Self as ::stomp::StompCommand>::spec(), #default, #collects_unknown);
+        }
+    }))
+}
+
+/// The spec used to find the command's arguments in the raw arguments,
+/// including those of its modes and any default subcommand.
+fn expand_command_spec(ast: &syn::MacroInput, attrs: &Attributes, fields: &[Field]) -> quote::Tokens {
+    let unknown = fields.iter().filter(|field| if let Field::Unknown(_) = **field { true } else { false }).count();
+    if unknown > 1 {
+        panic!("#[derive(StompCommand)] only supports a single unknown field, found {} on '{}'", unknown, ast.ident.as_ref());
+    }
+    let collects_unknown = unknown == 1;
+    let single_dash_longs = fields.iter().filter_map(|field| field.mode()).any(|mode| mode.is_sequence);
+
+    let specs = fields.iter().filter_map(|field| field.arg()).map(expand_spec);
+    let mut extra: Vec<_> = fields.iter().filter_map(|field| field.mode()).map(|mode| {
+        let ty = mode.ty;
+        quote! { args.extend(<#ty as ::stomp::StompMode>::specs()); }
+    }).collect();
+    if attrs.get_bool("explain_config") {
+        extra.push(quote! {
+            args.push(::stomp::ArgSpec {
+                name: "explain-config",
+                short: None,
+                long: Some("explain-config"),
+                aliases: ::std::vec::Vec::new(),
+                takes_value: false,
+                early: false,
+                index: None,
+            });
+        });
+    }
+
+    let subcommand = fields.iter().filter_map(|field| field.subcommand()).next();
+    // The default subcommand's arguments are given as ours, so we collect
+    // its unknown options and take its single dash longs too
+    let (collects_unknown, single_dash_longs) = match subcommand.and_then(|subcommand| subcommand.default.as_ref()) {
+        Some(default) => {
+            let ty = subcommand.unwrap().ty;
+            extra.push(quote! { args.extend(::stomp::subcommand_spec::<#ty>(#default).args); });
+            (quote! { #collects_unknown || ::stomp::subcommand_spec::<#ty>(#default).collects_unknown },
+             quote! { #single_dash_longs || ::stomp::subcommand_spec::<#ty>(#default).single_dash_longs })
+        }
+        None => (quote! { #collects_unknown }, quote! { #single_dash_longs }),
+    };
+    let args = if extra.is_empty() {
+        quote! { vec![#(#specs),*] }
+    } else {
+        quote! {{
+            let mut args = vec![#(#specs),*];
+            #(#extra)*
+            args
+        }}
+    };
+    let subcommands = match subcommand {
+        Some(subcommand) => {
+            let ty = subcommand.ty;
+            quote! { <#ty as ::stomp::StompCommands>::specs() }
+        }
+        None => quote! { ::std::vec::Vec::new() },
+    };
+    let chain = subcommand.map_or(false, |subcommand| subcommand.is_vec);
+    let aliases = attrs.get("alias").map(|a| a.values()).unwrap_or_else(Vec::new);
+
     quote! {
-        #name {
+        ::stomp::CommandSpec {
+            args: #args,
+            aliases: vec![#(#aliases),*],
+            subcommands: #subcommands,
+            chain: #chain,
+            collects_unknown: #collects_unknown,
+            single_dash_longs: #single_dash_longs,
+        }
+    }
+}
+
+/// Implements Default as parsing no arguments, ignoring the environment, so
+/// it always agrees with the command line.
+fn expand_default(ast: &syn::MacroInput, attrs: &Attributes, fields: &[Field]) -> Option<quote::Tokens> {
+    if !attrs.get_bool("impl_default") {
+        return None;
+    }
+
+    let ident = &ast.ident;
+    let name = command_name(ast, attrs);
+    let checks: Vec<_> = fields.iter().filter_map(|field| check_default_field(ast, field)).collect();
+    let has_default_mode = if checks.is_empty() {
+        None
+    } else {
+        Some(quote! { fn has_default_mode<T: ::stomp::DefaultMode>() {} })
+    };
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    Some(quote! {
+        impl #impl_generics ::std::default::Default for #ident #ty_generics #where_clause {
+            fn default() -> Self {
+                #has_default_mode
+                #(#checks)*
+                let ctx = ::stomp::Context::with_env(::std::collections::HashMap::new());
+                <Self as ::stomp::ParseApp>::try_parse_from_with(vec![#name], &ctx)
+                    .unwrap_or_else(|e| panic!("failed to parse the default for {}: {}", stringify!(#ident), e))
+            }
+        }
+    })
+}
+
+fn expand_parse(ast: &syn::MacroInput, attrs: &Attributes, fields: &[Field], groups: &[Group], names: &Names, matches: &syn::Ident, ctx: &syn::Ident) -> quote::Tokens {
+    let name = &ast.ident;
+    let app_name = command_name(ast, attrs);
+    let constraints = expand_constraints(fields, groups, names, matches, ctx);
+    let collects_unknown = fields.iter().any(|field| if let Field::Unknown(_) = *field { true } else { false });
+    let fields = fields.iter().map(|field| expand_parse_field(field, collects_unknown, &app_name, matches, ctx));
+    let parsed = quote! {{
+        let parsed = #name {
             #( #fields ),*
+        };
+        #(#constraints)*
+        parsed
+    }};
+    match attrs.get("validate") {
+        Some(validate) => {
+            let validate = syn::parse_path(validate.into()).expect("stomp-macros: invalid validate path");
+            quote! {{
+                let mut parsed = #parsed;
+                #validate(&mut parsed).map_err(|e| ::stomp::invalid(&e, #matches.usage()))?;
+                Ok(parsed)
+            }}
+        }
+        None => quote! { Ok(#parsed) },
+    }
+}
+
+/// The hidden `__stomp_shorts` giving the fields marked by `assign_shorts`
+/// their short names, avoiding every short the built command has.
+fn expand_shorts(ast: &syn::MacroInput, attrs: &Attributes, fields: &[Field]) -> Option<quote::Tokens> {
+    let wanted: Vec<_> = fields.iter()
+        .filter_map(|field| field.arg())
+        .filter(|arg| arg.wants_short)
+        .map(|arg| {
+            let name = arg.name;
+            let long = arg.long.as_ref().unwrap();
+            let required = arg.auto_short;
+            quote! { (#name, #long, #required) }
+        })
+        .collect();
+    if wanted.is_empty() {
+        return None;
+    }
+
+    let help = attrs.get("help_short").map(|a| a.into()).unwrap_or('h');
+    let version = attrs.get("version_short").map(|a| a.into()).unwrap_or('V');
+    let explicit = fields.iter()
+        .filter_map(|field| field.arg())
+        .filter_map(|arg| arg.short.as_ref().and_then(|short| short.chars().next()));
+    let modes = fields.iter().filter_map(|field| field.mode()).map(|mode| {
+        let ty = mode.ty;
+        quote! { taken.extend(<#ty as ::stomp::StompMode>::specs().into_iter().filter_map(|spec| spec.short)); }
+    });
+    let merged = fields.iter().filter_map(|field| field.subcommand()).filter_map(|subcommand| {
+        let ty = subcommand.ty;
+        subcommand.default.as_ref().map(|default| quote! {
+            taken.extend(::stomp::subcommand_spec::<#ty>(#default).args.into_iter().filter_map(|spec| spec.short));
+        })
+    });
+
+    let ident = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    Some(quote! {
+        impl #impl_generics #ident #ty_generics #where_clause {
+            #[doc(hidden)]
+            pub fn __stomp_shorts() -> ::std::vec::Vec<(&'static str, char)> {
+                let mut taken = vec![#help, #version];
+                taken.extend(&[#(#explicit),*]);
+                taken.extend(::stomp::reserved_shorts());
+                #(#modes)*
+                #(#merged)*
+                ::stomp::assign_shorts(taken, &[#(#wanted),*])
+            }
+        }
+    })
+}
+
+/// Checks explicit short names don't collide, then marks each field wanting
+/// one to be given a short name from the letters of its long name. That
+/// happens when the command is built, as only then are the shorts of modes,
+/// the default subcommand and the variant containing the command known.
+fn assign_shorts(ast: &syn::MacroInput, attrs: &Attributes, fields: &mut [Field]) {
+    let auto_short = attrs.get_bool("auto_short");
+
+    // Never take clap's help and version flags
+    let help = attrs.get("help_short").map(|a| a.into()).unwrap_or('h');
+    let version = attrs.get("version_short").map(|a| a.into()).unwrap_or('V');
+    let mut used: Vec<(char, &str)> = vec![(help, "help"), (version, "version")];
+    for arg in fields.iter().filter_map(|field| field.arg()) {
+        if let Some(short) = arg.short.as_ref().and_then(|short| short.chars().next()) {
+            if let Some(&(_, other)) = used.iter().find(|&&(used, _)| used == short) {
+                panic!("stomp-macros: short '-{}' of field '{}' on '{}' collides with '{}'", short, arg.ident.as_ref(), ast.ident.as_ref(), other);
+            }
+            used.push((short, arg.ident.as_ref()));
+        }
+    }
+
+    for field in fields.iter_mut() {
+        if let Field::Arg(ref mut arg) = *field {
+            let wanted = arg.auto_short || (auto_short && arg.short.is_none() && !arg.is_positional);
+            if !wanted || arg.short.is_some() || arg.long.is_none() {
+                continue;
+            }
+            // Fields that must have one can already fail on the shorts we know
+            let free = arg.long.as_ref().unwrap().chars()
+                .filter(|c| c.is_alphanumeric())
+                .flat_map(|c| c.to_lowercase().chain(c.to_uppercase()))
+                .any(|candidate| !used.iter().any(|&(used, _)| used == candidate));
+            if !free && arg.auto_short {
+                panic!("stomp-macros: no free short name for field '{}' on '{}'", arg.ident.as_ref(), ast.ident.as_ref());
+            }
+            arg.wants_short = true;
         }
     }
 }
 
 pub fn expand(ast: &syn::MacroInput, attrs: &Attributes, field_attrs: &FieldAttributes) -> quote::Tokens {
-    let fields = match ast.body {
+    let rename = Rename::from_attrs(attrs);
+    let mut fields: Vec<_> = match ast.body {
         syn::Body::Struct(syn::VariantData::Unit) => {
             Vec::new()
         }
         syn::Body::Struct(syn::VariantData::Struct(ref fields)) => {
             fields.iter()
-                .map(|field| Field::from((field, field_attrs.get(field))))
+                .map(|field| Field::from((field, field_attrs.get(field), rename)))
                 .collect()
         }
         syn::Body::Struct(syn::VariantData::Tuple(_)) => {
@@ -204,10 +1533,68 @@ pub fn expand(ast: &syn::MacroInput, attrs: &Attributes, field_attrs: &FieldAttr
         }
     };
 
+    if let Some(prefix) = attrs.get("env_prefix").map(|a| a.into(): &str) {
+        for field in &mut fields {
+            if let Field::Arg(ref mut arg) = *field {
+                arg.env = arg.env.take().map(|var| prefix.to_owned() + &var);
+            }
+        }
+    }
+
+    assign_shorts(ast, attrs, &mut fields);
+
+    for (i, field) in fields.iter_mut().enumerate() {
+        if let Field::Arg(ref mut arg) = *field {
+            arg.display_order = i;
+        }
+    }
+
+    // Positional arguments are indexed in field order, skipping any given
+    // explicitly
+    let mut used: Vec<u64> = fields.iter().filter_map(|field| field.arg()).filter_map(|arg| arg.index).collect();
+    let mut next = 1;
+    for field in &mut fields {
+        if let Field::Arg(ref mut arg) = *field {
+            if arg.is_positional && !arg.is_raw && arg.index.is_none() {
+                while used.contains(&next) {
+                    next += 1;
+                }
+                arg.index = Some(next);
+                used.push(next);
+            }
+        }
+    }
+
+    // A raw field takes everything after the other positional arguments
+    let last_index = fields.iter().filter_map(|field| field.arg()).filter_map(|arg| arg.index).max().unwrap_or(0);
+    for field in &mut fields {
+        if let Field::Arg(ref mut arg) = *field {
+            if arg.is_raw && arg.index.is_none() {
+                arg.index = Some(last_index + 1);
+            }
+        }
+    }
+
     let ident = &ast.ident;
+    let groups = extract_groups(ast, attrs, &fields);
+    let names = Names::new(ast, &fields, &groups);
     let command = expand_command(ast, attrs, &fields);
+    let args = expand_args(&fields, &names);
+    let arg_groups = expand_groups(ast, &groups, &fields);
+    let early_command = expand_early_command(ast, attrs, &fields);
+    let spec = expand_command_spec(ast, attrs, &fields);
     let matches = "matches".into(): syn::Ident;
-    let parse = expand_parse(ast, &fields, &matches);
+    let ctx = "ctx".into(): syn::Ident;
+    let parse = expand_parse(ast, attrs, &fields, &groups, &names, &matches, &ctx);
+    let check_default_subcommand = expand_check_default_subcommand(&fields);
+    let check_reserved_shorts = expand_check_reserved_shorts(attrs);
+    let check_chain = expand_check_chain(&fields);
+    let check_mode_args = expand_check_mode_args(&fields);
+    let shorts = expand_shorts(ast, attrs, &fields);
+    let (early_ident, early, parse_early) = expand_early(ast, &fields, &matches, &ctx);
+    let explain: Vec<_> = fields.iter().filter_map(|field| expand_explain_field(field, &matches, &ctx)).collect();
+    let (sources_ident, sources, build_sources) = expand_sources(ast, attrs, &fields);
+    let default = expand_default(ast, attrs, &fields);
     let allow_unused = syn::Attribute {
         style: syn::AttrStyle::Outer,
         value: syn::MetaItem::List(syn::Ident::from("allow"), vec![
@@ -220,13 +1607,81 @@ pub fn expand(ast: &syn::MacroInput, attrs: &Attributes, field_attrs: &FieldAttr
     let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
     quote! {
         impl #impl_generics ::stomp::StompCommand for #ident #ty_generics #where_clause {
+            type Sources = #sources_ident;
+            type Early = #early_ident;
             fn command() -> ::clap::App<'static, 'static> {
+                #check_default_subcommand
+                #check_reserved_shorts
+                #check_chain
+                #(#check_mode_args)*
                 #command
             }
+            fn args() -> ::std::vec::Vec<::clap::Arg<'static, 'static>> {
+                #args
+            }
+            fn groups() -> ::std::vec::Vec<::clap::ArgGroup<'static>> {
+                #arg_groups
+            }
+            fn early_command() -> ::clap::App<'static, 'static> {
+                #early_command
+            }
+            #allow_unused
+            fn parse_early(#matches: &::clap::ArgMatches, #ctx: &::stomp::Context) -> ::clap::Result<#early_ident> {
+                #parse_early
+            }
+            fn spec() -> ::stomp::CommandSpec {
+                #spec
+            }
             #allow_unused
-            fn parse(#matches: &::clap::ArgMatches) -> Self {
+            fn parse_with(#matches: &::clap::ArgMatches, #ctx: &::stomp::Context) -> ::clap::Result<Self> {
                 #parse
             }
+            #allow_unused
+            fn explain_with(#matches: &::clap::ArgMatches, #ctx: &::stomp::Context) {
+                #(#explain)*
+            }
+            fn sources(sources: &::stomp::Sources) -> #sources_ident {
+                #build_sources
+            }
         }
+
+        #shorts
+
+        #sources
+
+        #early
+
+        #default
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use syn;
+
+    use attrs;
+
+    fn expand(source: &str) -> String {
+        let mut ast = syn::parse_macro_input(source).unwrap();
+        let (attrs, field_attrs) = attrs::extract_attrs(&mut ast);
+        super::expand(&ast, &attrs, &field_attrs).to_string()
+    }
+
+    #[test]
+    #[should_panic(expected = "chained subcommand field 'steps' can't have a default")]
+    fn chains_have_no_default() {
+        expand("struct Tool { #[stomp(subcommand, default = \"build\")] steps: Vec<Steps> }");
+    }
+
+    #[test]
+    fn chains_check_their_positionals() {
+        let expanded = expand("struct Tool { #[stomp(subcommand)] steps: Vec<Steps> }");
+        assert!(expanded.contains("check_chain :: < Steps >"));
+    }
+
+    #[test]
+    #[should_panic(expected = "refers to unknown field 'forse'")]
+    fn relations_name_existing_fields() {
+        expand("struct Tool { #[stomp(conflicts_with = \"forse\")] dry_run: bool, force: bool }");
     }
 }
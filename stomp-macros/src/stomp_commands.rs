@@ -1,15 +1,168 @@
 use syn;
 use quote;
 
-pub fn expand_commands(cmds: &[(&syn::Ident, &syn::Ty)]) -> quote::Tokens {
-    let types = cmds.iter().map(|&(_ident, ty)| ty);
-    quote! { vec![ #(<#types as ::stomp::StompCommand>::command()),* ] }
+use app;
+use attrs::{ self, Attributes, FieldAttributes };
+
+struct Command<'a> {
+    ident: &'a syn::Ident,
+    ty: &'a syn::Ty,
+    attrs: &'a Attributes,
+    is_group: bool,
+}
+
+impl<'a> Command<'a> {
+    fn name(&self) -> String {
+        self.ident.as_ref().to_lowercase()
+    }
+
+    /// Calls `f` on the command's type with the variant's help and version
+    /// shorts reserved, so the command doesn't give them to its arguments.
+    /// Every variant sets them, so none leak in from an outer command.
+    fn reserving_shorts(&self, f: quote::Tokens) -> quote::Tokens {
+        let shorts = ["help_short", "version_short"].iter()
+            .filter_map(|key| self.attrs.get(key))
+            .map(|a| a.into(): char);
+        quote! { ::stomp::reserve_shorts(&[#(#shorts),*], #f) }
+    }
 }
 
-fn expand_parse(me: &syn::Ident, cmds: &[(&syn::Ident, &syn::Ty)], name: &syn::Ident, matches: &syn::Ident) -> quote::Tokens {
-    let variants = cmds.iter().map(|&(ident, ty)| {
-        let name = ident.as_ref().to_lowercase();
-        quote! { #name => #me::#ident(<#ty as ::stomp::StompCommand>::parse(#matches)) }
+fn expand_command(cmd: &Command) -> quote::Tokens {
+    let ty = cmd.ty;
+    let settings = app::expand_settings(cmd.attrs);
+    if cmd.is_group {
+        let name = cmd.name();
+        let about = match cmd.attrs.get("about") {
+            Some(about) => quote! { #about },
+            None => {
+                let ref summary = cmd.attrs.summary;
+                quote! { #summary }
+            }
+        };
+        let after_help = match cmd.attrs.get("after_help") {
+            Some(after_help) => quote! { #after_help },
+            None => attrs::expand_help("", &cmd.attrs.docs),
+        };
+        quote! {
+            ::clap::App::new(#name)
+                .about(#about)
+                .after_help(#after_help)
+                .setting(::clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommands(<#ty as ::stomp::StompCommands>::commands())
+                #settings
+        }
+    } else {
+        // The variant's attributes override those of the command
+        let about = cmd.attrs.get("about").map(|a| quote! { .about(#a) });
+        let after_help = cmd.attrs.get("after_help").map(|a| quote! { .after_help(#a) });
+        let command = cmd.reserving_shorts(quote! { <#ty as ::stomp::StompCommand>::command });
+        quote! {
+            #command
+                #about
+                #after_help
+                #settings
+        }
+    }
+}
+
+fn expand_commands(cmds: &[Command]) -> quote::Tokens {
+    let commands = cmds.iter().map(expand_command);
+    quote! { vec![ #(#commands),* ] }
+}
+
+fn expand_args(cmds: &[Command], name: &syn::Ident) -> quote::Tokens {
+    let variants = cmds.iter().map(|cmd| {
+        let name = cmd.name();
+        let ty = cmd.ty;
+        if cmd.is_group {
+            quote! { #name => ::std::vec::Vec::new() }
+        } else {
+            let args = cmd.reserving_shorts(quote! { <#ty as ::stomp::StompCommand>::args });
+            quote! { #name => #args }
+        }
+    });
+    quote! {
+        match #name {
+            #(#variants,)*
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn expand_groups(cmds: &[Command], name: &syn::Ident) -> quote::Tokens {
+    let variants = cmds.iter().map(|cmd| {
+        let name = cmd.name();
+        let ty = cmd.ty;
+        if cmd.is_group {
+            quote! { #name => ::std::vec::Vec::new() }
+        } else {
+            quote! { #name => <#ty as ::stomp::StompCommand>::groups() }
+        }
+    });
+    quote! {
+        match #name {
+            #(#variants,)*
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn expand_specs(cmds: &[Command]) -> quote::Tokens {
+    let specs = cmds.iter().map(|cmd| {
+        let name = cmd.name();
+        let ty = cmd.ty;
+        let aliases = cmd.attrs.get("alias").map(|a| a.values()).unwrap_or_else(Vec::new);
+        let spec = if cmd.is_group {
+            quote! { ::stomp::group_spec::<#ty> }
+        } else {
+            let spec = cmd.reserving_shorts(quote! { <#ty as ::stomp::StompCommand>::spec });
+            quote! {{
+                fn spec() -> ::stomp::CommandSpec {
+                    #spec
+                }
+                spec
+            }}
+        };
+        quote! {
+            ::stomp::SubcommandSpec {
+                name: #name,
+                aliases: vec![#(#aliases),*],
+                spec: #spec,
+            }
+        }
+    });
+    quote! { vec![#(#specs),*] }
+}
+
+fn expand_parse(me: &syn::Ident, cmds: &[Command], name: &syn::Ident, matches: &syn::Ident, ctx: &syn::Ident) -> quote::Tokens {
+    let variants = cmds.iter().map(|cmd| {
+        let name = cmd.name();
+        let ident = cmd.ident;
+        let ty = cmd.ty;
+        let parsed = if cmd.is_group {
+            quote! {
+                match #matches.subcommand() {
+                    (name, Some(matches)) => <#ty as ::stomp::StompCommands>::parse_with(name, matches, &#ctx.subcommand(name))?,
+                    (_, None) => unreachable!(),
+                }
+            }
+        } else {
+            quote! { <#ty as ::stomp::StompCommand>::parse_with(#matches, #ctx)? }
+        };
+        // The variant's hook gets the command it contains
+        match cmd.attrs.get("validate") {
+            Some(validate) => {
+                let validate = syn::parse_path(validate.into()).expect("stomp-macros: invalid validate path");
+                quote! {
+                    #name => {
+                        let mut parsed = #parsed;
+                        #validate(&mut parsed).map_err(|e| ::stomp::invalid(&e, #matches.usage()))?;
+                        Ok(#me::#ident(parsed))
+                    }
+                }
+            }
+            None => quote! { #name => Ok(#me::#ident(#parsed)) },
+        }
     });
     quote! {
         match #name {
@@ -19,10 +172,104 @@ fn expand_parse(me: &syn::Ident, cmds: &[(&syn::Ident, &syn::Ty)], name: &syn::I
     }
 }
 
-pub fn expand(ast: &syn::MacroInput) -> quote::Tokens {
+fn expand_explain(cmds: &[Command], name: &syn::Ident, matches: &syn::Ident, ctx: &syn::Ident) -> quote::Tokens {
+    let variants = cmds.iter().map(|cmd| {
+        let name = cmd.name();
+        let ty = cmd.ty;
+        if cmd.is_group {
+            quote! {
+                #name => if let (name, Some(matches)) = #matches.subcommand() {
+                    <#ty as ::stomp::StompCommands>::explain_with(name, matches, &#ctx.subcommand(name))
+                }
+            }
+        } else {
+            quote! { #name => <#ty as ::stomp::StompCommand>::explain_with(#matches, #ctx) }
+        }
+    });
+    quote! {
+        match #name {
+            #(#variants,)*
+            _ => (),
+        }
+    }
+}
+
+/// A hidden function for each variant that can be a default subcommand, so
+/// a `#[stomp(subcommand, default = "..")]` naming anything else fails to
+/// compile.
+fn expand_defaults(ast: &syn::MacroInput, cmds: &[Command]) -> quote::Tokens {
+    let ident = &ast.ident;
+    let defaults = cmds.iter().filter(|cmd| !cmd.is_group).map(|cmd| {
+        let marker = syn::Ident::from(format!("__stomp_default_{}", cmd.name()));
+        quote! {
+            #[doc(hidden)]
+            #[allow(dead_code)]
+            pub fn #marker() {}
+        }
+    });
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    quote! {
+        impl #impl_generics #ident #ty_generics #where_clause {
+            #(#defaults)*
+        }
+    }
+}
+
+fn expand_run(ast: &syn::MacroInput, attrs: &Attributes, cmds: &[Command]) -> Option<quote::Tokens> {
+    let run = match attrs.get("run") {
+        Some(run) => run,
+        None => return None,
+    };
+    let options = run.nested().first();
+    let option = |key| options.and_then(|options| options.get(key)).map(|a| a.into(): &str);
+
+    let context = syn::parse_type(option("context").unwrap_or("()"))
+        .expect("stomp-macros: invalid run context type");
+    let error = syn::parse_type(option("error").unwrap_or("::std::boxed::Box<::std::error::Error + Send + Sync>"))
+        .expect("stomp-macros: invalid run error type");
+    let before = option("before").map(|before| {
+        let before = syn::parse_path(before).expect("stomp-macros: invalid run before hook");
+        quote! { #before(ctx)?; }
+    });
+    let result = "result".into(): syn::Ident;
+    let after = match option("after") {
+        Some(after) => {
+            let after = syn::parse_path(after).expect("stomp-macros: invalid run after hook");
+            quote! { #after(ctx, #result) }
+        }
+        None => quote! { #result },
+    };
+
+    let me = &ast.ident;
+    let variants = cmds.iter().map(|cmd| {
+        let ident = cmd.ident;
+        let ty = cmd.ty;
+        quote! {
+            #me::#ident(command) => <#ty as ::stomp::Run<#context>>::run(command, ctx)
+                .map_err(::std::convert::From::from)
+        }
+    });
+
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    Some(quote! {
+        impl #impl_generics ::stomp::Run<#context> for #me #ty_generics #where_clause {
+            type Err = #error;
+            fn run(self, ctx: &mut #context) -> ::std::result::Result<(), #error> {
+                #before
+                let #result = match self {
+                    #(#variants,)*
+                };
+                #after
+            }
+        }
+    })
+}
+
+pub fn expand(ast: &syn::MacroInput, attrs: &Attributes, variant_attrs: &FieldAttributes) -> quote::Tokens {
     let ident = &ast.ident;
     let name = "name".into(): syn::Ident;
     let matches = "matches".into(): syn::Ident;
+    let ctx = "ctx".into(): syn::Ident;
 
     let cmds: Vec<_> = match ast.body {
         syn::Body::Enum(ref variants) => {
@@ -30,7 +277,13 @@ pub fn expand(ast: &syn::MacroInput) -> quote::Tokens {
                 .map(|variant| match variant.data {
                     syn::VariantData::Tuple(ref fields) => {
                         if fields.len() == 1 {
-                            (&variant.ident, &fields[0].ty)
+                            let attrs = variant_attrs.get_variant(variant);
+                            Command {
+                                ident: &variant.ident,
+                                ty: &fields[0].ty,
+                                attrs: attrs,
+                                is_group: attrs.get_bool("subcommands"),
+                            }
                         } else {
                             panic!("#[derive(StompCommands)] does not support enum variants with multiple fields")
                         }
@@ -50,16 +303,38 @@ pub fn expand(ast: &syn::MacroInput) -> quote::Tokens {
     };
 
     let commands = expand_commands(&cmds);
-    let parse = expand_parse(ident, &cmds, &name, &matches);
+    let args = expand_args(&cmds, &name);
+    let groups = expand_groups(&cmds, &name);
+    let specs = expand_specs(&cmds);
+    let parse = expand_parse(ident, &cmds, &name, &matches, &ctx);
+    let explain = expand_explain(&cmds, &name, &matches, &ctx);
+    let defaults = expand_defaults(ast, &cmds);
+    let run = expand_run(ast, attrs, &cmds);
     let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
     quote! {
         impl #impl_generics ::stomp::StompCommands for #ident #ty_generics #where_clause {
             fn commands() -> ::std::vec::Vec<::clap::App<'static, 'static>> {
                 #commands
             }
-            fn parse(#name: &str, #matches: &::clap::ArgMatches) -> Self {
+            fn args(#name: &str) -> ::std::vec::Vec<::clap::Arg<'static, 'static>> {
+                #args
+            }
+            fn groups(#name: &str) -> ::std::vec::Vec<::clap::ArgGroup<'static>> {
+                #groups
+            }
+            fn specs() -> ::std::vec::Vec<::stomp::SubcommandSpec> {
+                #specs
+            }
+            fn parse_with(#name: &str, #matches: &::clap::ArgMatches, #ctx: &::stomp::Context) -> ::clap::Result<Self> {
                 #parse
             }
+            fn explain_with(#name: &str, #matches: &::clap::ArgMatches, #ctx: &::stomp::Context) {
+                #explain
+            }
         }
+
+        #defaults
+
+        #run
     }
 }
@@ -0,0 +1,221 @@
+use syn;
+use quote;
+
+use attrs::{ self, Attributes, FieldAttributes };
+use field::Rename;
+
+struct Variant<'a> {
+    ident: &'a syn::Ident,
+    name: String,
+    attrs: &'a Attributes,
+    /// The type of the value for variants that take one
+    ty: Option<&'a syn::Ty>,
+    is_default: bool,
+}
+
+impl<'a> Variant<'a> {
+    fn long(&self) -> &str {
+        self.attrs.get("long").map(|a| a.into(): &str).unwrap_or(&self.name)
+    }
+
+    fn short(&self) -> Option<char> {
+        self.attrs.get("short").map(|s| s.into())
+    }
+}
+
+fn expand_arg(variant: &Variant) -> quote::Tokens {
+    let name: &str = &variant.name;
+    let long = variant.long();
+    let short = variant.short().map(|short| {
+        let short = short.to_string();
+        quote! { .short(#short) }
+    });
+    let long = (variant.attrs.summary.to_string() + "\n\n" + &variant.attrs.docs).trim().to_string();
+    let docs = attrs::expand_help(variant.attrs.summary.trim(), &long);
+    let value = variant.ty.map(|ty| quote! {
+        .takes_value(true)
+        .validator(|s| {
+            <#ty as ::std::str::FromStr>::from_str(&s)
+                .map(|_| ())
+                .map_err(|e| format!("failed to parse value {:?} for argument '{}': {}", s, #name, e))
+        })
+    });
+
+    quote! {
+        ::clap::Arg::with_name(#name)
+            .long(#long)
+            #short
+            .help(#docs)
+            #value
+    }
+}
+
+/// The argument for a `#[stomp(sequence)]` field, where every occurrence is
+/// a separate variant.
+fn expand_repeated_arg(variant: &Variant) -> quote::Tokens {
+    let arg = expand_arg(variant);
+    let values = variant.ty.map(|_| quote! { .number_of_values(1) });
+    quote! {
+        #arg
+            .multiple(true)
+            #values
+    }
+}
+
+fn expand_spec(variant: &Variant) -> quote::Tokens {
+    let name: &str = &variant.name;
+    let long = variant.long();
+    let short = match variant.short() {
+        Some(short) => quote! { Some(#short) },
+        None => quote! { None },
+    };
+    let takes_value = variant.ty.is_some();
+    quote! {
+        ::stomp::ArgSpec {
+            name: #name,
+            short: #short,
+            long: Some(#long),
+            aliases: ::std::vec::Vec::new(),
+            takes_value: #takes_value,
+            early: false,
+            index: None,
+        }
+    }
+}
+
+fn expand_parse_occurrence(me: &syn::Ident, variant: &Variant, name: &syn::Ident, value: &syn::Ident) -> quote::Tokens {
+    let ident = variant.ident;
+    let ref variant_name = variant.name;
+    if variant.ty.is_some() {
+        quote! {
+            if #name == #variant_name {
+                return #value.map(|value| #me::#ident(value.parse().unwrap()));
+            }
+        }
+    } else {
+        quote! {
+            if #name == #variant_name {
+                return Some(#me::#ident);
+            }
+        }
+    }
+}
+
+fn expand_parse(me: &syn::Ident, variant: &Variant, matches: &syn::Ident) -> quote::Tokens {
+    let ident = variant.ident;
+    let ref name = variant.name;
+    if variant.ty.is_some() {
+        quote! {
+            if let Some(value) = #matches.value_of(#name) {
+                return Some(#me::#ident(value.parse().unwrap()));
+            }
+        }
+    } else {
+        quote! {
+            if #matches.is_present(#name) {
+                return Some(#me::#ident);
+            }
+        }
+    }
+}
+
+pub fn expand(ast: &syn::MacroInput, attrs: &Attributes, variant_attrs: &FieldAttributes) -> quote::Tokens {
+    let ident = &ast.ident;
+    // Variants are named like fields, e.g. `DryRun` is `--dry-run`
+    let rename = Rename::from_attrs(attrs);
+    let matches = "matches".into(): syn::Ident;
+
+    let variants: Vec<_> = match ast.body {
+        syn::Body::Enum(ref variants) => {
+            variants.iter()
+                .map(|variant| {
+                    let attrs = variant_attrs.get_variant(variant);
+                    let ty = match variant.data {
+                        syn::VariantData::Unit => None,
+                        syn::VariantData::Tuple(ref fields) if fields.len() == 1 => Some(&fields[0].ty),
+                        syn::VariantData::Tuple(_) => {
+                            panic!("#[derive(StompMode)] does not support enum variants with multiple fields")
+                        }
+                        syn::VariantData::Struct(_) => {
+                            panic!("#[derive(StompMode)] does not support struct enum variants")
+                        }
+                    };
+                    let is_default = attrs.get_bool("default");
+                    if is_default && ty.is_some() {
+                        panic!("#[derive(StompMode)] only supports unit variants as the default");
+                    }
+                    Variant {
+                        ident: &variant.ident,
+                        name: attrs.get("name").map(|a| (a.into(): &str).to_owned())
+                            .unwrap_or_else(|| rename.apply(variant.ident.as_ref())),
+                        attrs: attrs,
+                        ty: ty,
+                        is_default: is_default,
+                    }
+                })
+                .collect()
+        }
+        syn::Body::Struct(_) => {
+            panic!("#[derive(StompMode)] is not supported on structs")
+        }
+    };
+
+    let defaults: Vec<_> = variants.iter().filter(|variant| variant.is_default).collect();
+    if defaults.len() > 1 {
+        panic!("#[derive(StompMode)] only supports a single default variant, found {} on '{}'", defaults.len(), ident.as_ref());
+    }
+    let default = match defaults.first() {
+        Some(variant) => {
+            let variant = variant.ident;
+            quote! { Some(#ident::#variant) }
+        }
+        None => quote! { None },
+    };
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    let default_mode = if defaults.is_empty() {
+        None
+    } else {
+        Some(quote! {
+            impl #impl_generics ::stomp::DefaultMode for #ident #ty_generics #where_clause {}
+        })
+    };
+
+    let args = variants.iter().map(expand_arg);
+    let repeated_args = variants.iter().map(expand_repeated_arg);
+    let specs = variants.iter().map(expand_spec);
+    let names = variants.iter().map(|variant| &variant.name);
+    let parse = variants.iter().map(|variant| expand_parse(ident, variant, &matches));
+    let name = "name".into(): syn::Ident;
+    let value = "value".into(): syn::Ident;
+    let parse_occurrence = variants.iter().map(|variant| expand_parse_occurrence(ident, variant, &name, &value));
+    quote! {
+        impl #impl_generics ::stomp::StompMode for #ident #ty_generics #where_clause {
+            fn args() -> ::std::vec::Vec<::clap::Arg<'static, 'static>> {
+                vec![#(#args),*]
+            }
+            fn names() -> ::std::vec::Vec<&'static str> {
+                vec![#(#names),*]
+            }
+            fn parse(#matches: &::clap::ArgMatches) -> Option<Self> {
+                #(#parse)*
+                None
+            }
+            fn default_mode() -> Option<Self> {
+                #default
+            }
+            fn repeated_args() -> ::std::vec::Vec<::clap::Arg<'static, 'static>> {
+                vec![#(#repeated_args),*]
+            }
+            fn specs() -> ::std::vec::Vec<::stomp::ArgSpec> {
+                vec![#(#specs),*]
+            }
+            #[allow(unused_variables)]
+            fn parse_occurrence(#name: &str, #value: Option<&str>) -> Option<Self> {
+                #(#parse_occurrence)*
+                None
+            }
+        }
+
+        #default_mode
+    }
+}
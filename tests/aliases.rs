@@ -0,0 +1,56 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use stomp::{ ParseApp, StompCommand };
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "fetch")]
+struct Fetch {
+    #[stomp(visible_alias = "dest", deprecated_alias = "target")]
+    output: Option<String>,
+    #[stomp(alias = "quiet")]
+    silent: bool,
+    #[stomp(hidden)]
+    trace_internals: bool,
+    #[stomp(deprecated = "it is always on now")]
+    follow_redirects: bool,
+}
+
+fn help() -> String {
+    let mut help = Vec::new();
+    Fetch::command().write_help(&mut help).unwrap();
+    String::from_utf8(help).unwrap()
+}
+
+#[test]
+fn aliases_parse_as_the_field() {
+    let fetch = Fetch::try_parse_from(vec!["fetch", "--dest", "a", "--quiet"]).unwrap();
+    assert_eq!(fetch.output, Some("a".to_owned()));
+    assert!(fetch.silent);
+}
+
+#[test]
+fn deprecated_names_still_parse() {
+    let fetch = Fetch::try_parse_from(vec!["fetch", "--target", "b", "--follow-redirects"]).unwrap();
+    assert_eq!(fetch.output, Some("b".to_owned()));
+    assert!(fetch.follow_redirects);
+}
+
+#[test]
+fn hidden_fields_parse_but_stay_out_of_help() {
+    let fetch = Fetch::try_parse_from(vec!["fetch", "--trace-internals"]).unwrap();
+    assert!(fetch.trace_internals);
+
+    let help = help();
+    assert!(help.contains("--dest"));
+    assert!(!help.contains("--target"));
+    assert!(!help.contains("--quiet"));
+    assert!(!help.contains("--trace-internals"));
+    assert!(!help.contains("--follow-redirects"));
+}
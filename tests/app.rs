@@ -0,0 +1,81 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use stomp::{ ParseApp, StompCommand };
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "tool", settings = "ArgRequiredElseHelp")]
+#[stomp(about = "Does things", before_help = "Before everything", after_help = "After everything")]
+#[stomp(usage = "tool [OPTIONS] <THING>")]
+struct Tool {
+    #[stomp(index = 1)]
+    thing: Option<String>,
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "remote", help_short = 'H')]
+struct Remote {
+    #[stomp(short = 'h')]
+    host: Option<String>,
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "clash")]
+struct Clash {
+    #[stomp(short = 'h')]
+    host: Option<String>,
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "moved", help_short = 'x')]
+struct Moved {
+    #[stomp(short = 'x')]
+    exclude: Option<String>,
+}
+
+#[test]
+fn settings_are_applied() {
+    let err = Tool::try_parse_from(vec!["tool"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::MissingArgumentOrSubcommand);
+
+    let tool = Tool::try_parse_from(vec!["tool", "x"]).unwrap();
+    assert_eq!(tool.thing, Some("x".to_owned()));
+}
+
+#[test]
+fn help_text_is_overridden() {
+    let mut help = Vec::new();
+    Tool::command().write_help(&mut help).unwrap();
+    let help = String::from_utf8(help).unwrap();
+    assert!(help.contains("Does things"));
+    assert!(help.contains("Before everything"));
+    assert!(help.contains("After everything"));
+    assert!(help.contains("tool [OPTIONS] <THING>"));
+}
+
+#[test]
+fn help_short_frees_its_default() {
+    let remote = Remote::try_parse_from(vec!["remote", "-h", "example.com"]).unwrap();
+    assert_eq!(remote.host, Some("example.com".to_owned()));
+
+    let err = Remote::try_parse_from(vec!["remote", "-H"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::HelpDisplayed);
+}
+
+#[test]
+#[should_panic(expected = "short '-h' of argument 'host' collides with a help or version short")]
+fn shorts_cant_take_the_help_short() {
+    Clash::command();
+}
+
+#[test]
+#[should_panic(expected = "short '-x' of argument 'exclude' collides with a help or version short")]
+fn shorts_cant_take_a_moved_help_short() {
+    Moved::command();
+}
@@ -0,0 +1,90 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use stomp::ParseApp;
+
+#[derive(StompCommand, Debug, PartialEq)]
+struct Clean;
+
+#[derive(StompCommand, Debug, PartialEq)]
+#[stomp(alias = "b")]
+struct Build {
+    release: bool,
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+struct Test {
+    #[stomp(short = 'q')]
+    quiet: bool,
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+struct Run {
+    #[stomp(arg)]
+    target: String,
+}
+
+#[derive(StompCommands, Debug, PartialEq)]
+enum Steps {
+    Clean(Clean),
+    Build(Build),
+    #[stomp(alias = "t")]
+    Test(Test),
+}
+
+#[derive(StompCommands, Debug, PartialEq)]
+enum Positional {
+    Run(Run),
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+#[stomp(name = "tool")]
+struct Tool {
+    #[stomp(subcommand)]
+    steps: Vec<Steps>,
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "bad")]
+struct Bad {
+    #[stomp(subcommand)]
+    steps: Vec<Positional>,
+}
+
+#[test]
+fn steps_are_parsed_in_order() {
+    let tool = Tool::try_parse_from(vec!["tool", "clean", "build", "--release", "test", "-q"]).unwrap();
+    assert_eq!(tool.steps, vec![
+        Steps::Clean(Clean),
+        Steps::Build(Build { release: true }),
+        Steps::Test(Test { quiet: true }),
+    ]);
+}
+
+#[test]
+fn steps_can_be_aliased() {
+    let tool = Tool::try_parse_from(vec!["tool", "clean", "b", "t"]).unwrap();
+    assert_eq!(tool.steps, vec![
+        Steps::Clean(Clean),
+        Steps::Build(Build { release: false }),
+        Steps::Test(Test { quiet: false }),
+    ]);
+}
+
+#[test]
+fn unknown_steps_are_errors() {
+    let err = Tool::try_parse_from(vec!["tool", "clean", "deploy"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::UnrecognizedSubcommand);
+}
+
+#[test]
+#[should_panic(expected = "chained subcommand 'run' can't take positional arguments")]
+fn chained_positionals_are_rejected() {
+    let _ = Bad::try_parse_from(vec!["bad", "run", "x"]);
+}
@@ -0,0 +1,78 @@
+#![cfg(feature = "config")]
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{ self, File };
+use std::io::Write;
+
+use stomp::{ Context, ParseApp };
+use stomp::config::Config;
+
+#[derive(StompCommand, Debug)]
+struct Add {
+    url: Option<String>,
+}
+
+#[derive(StompCommands, Debug)]
+enum Commands {
+    Add(Add),
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "app")]
+struct App {
+    #[stomp(env = "APP_PORT", default_value = "80")]
+    port: u32,
+    #[stomp(subcommand)]
+    command: Commands,
+}
+
+fn context(vars: &[(&str, &str)], config: Config) -> Context {
+    let env: HashMap<String, String> = vars.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect();
+    let mut ctx = Context::with_env(env);
+    ctx.add_layer(config);
+    ctx
+}
+
+#[test]
+fn config_is_below_the_environment_and_command_line() {
+    let path = env::temp_dir().join("stomp-test-precedence.toml");
+    File::create(&path).unwrap().write_all(b"port = 8080\n[add]\nurl = \"https://example.com\"\n").unwrap();
+
+    let app = App::try_parse_from_with(vec!["app", "add"], &context(&[], Config::from_files(&[&path]).unwrap())).unwrap();
+    assert_eq!(app.port, 8080);
+    match app.command {
+        Commands::Add(ref add) => assert_eq!(add.url, Some("https://example.com".to_owned())),
+    }
+
+    let app = App::try_parse_from_with(vec!["app", "add"], &context(&[("APP_PORT", "9090")], Config::from_files(&[&path]).unwrap())).unwrap();
+    assert_eq!(app.port, 9090);
+
+    let app = App::try_parse_from_with(vec!["app", "--port", "1", "add", "--url", "x"], &context(&[("APP_PORT", "9090")], Config::from_files(&[&path]).unwrap())).unwrap();
+    assert_eq!(app.port, 1);
+    match app.command {
+        Commands::Add(ref add) => assert_eq!(add.url, Some("x".to_owned())),
+    }
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn invalid_config_values_name_the_file_and_key() {
+    let path = env::temp_dir().join("stomp-test-invalid.toml");
+    File::create(&path).unwrap().write_all(b"port = \"many\"\n").unwrap();
+
+    let err = App::try_parse_from_with(vec!["app", "add"], &context(&[], Config::from_files(&[&path]).unwrap())).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::ValueValidation);
+    assert!(err.message.contains(&format!("config file '{}' key 'port'", path.display())), "{}", err.message);
+
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,84 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use stomp::{ ParseApp, StompCommand };
+
+fn even(value: &u32) -> Result<(), String> {
+    if value % 2 == 0 {
+        Ok(())
+    } else {
+        Err("must be even".to_owned())
+    }
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "listen")]
+struct Listen {
+    /// The port to listen on
+    #[stomp(range = "1..=65535")]
+    port: Option<u32>,
+    /// Names of the allowed hosts
+    #[stomp(pattern = "^[a-z][a-z0-9.-]*$")]
+    allow: Vec<String>,
+    #[stomp(min_len = 3, max_len = 8)]
+    tag: Option<String>,
+    #[stomp(validator = "even")]
+    workers: Option<u32>,
+}
+
+fn error(args: Vec<&str>) -> clap::Error {
+    let err = Listen::try_parse_from(args).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::ValueValidation);
+    err
+}
+
+#[test]
+fn values_within_constraints_parse() {
+    let listen = Listen::try_parse_from(vec!["listen", "--port", "65535", "--allow", "a.example", "--tag", "abc", "--workers", "4"]).unwrap();
+    assert_eq!(listen.port, Some(65535));
+    assert_eq!(listen.allow, vec!["a.example".to_owned()]);
+    assert_eq!(listen.tag, Some("abc".to_owned()));
+    assert_eq!(listen.workers, Some(4));
+}
+
+#[test]
+fn range_errors_name_the_argument() {
+    let err = error(vec!["listen", "--port", "0"]);
+    assert!(err.message.contains("value \"0\" for argument 'port' is not in the range 1..=65535"));
+    error(vec!["listen", "--port", "65536"]);
+}
+
+#[test]
+fn pattern_is_checked_on_every_element() {
+    let err = error(vec!["listen", "--allow", "a.example", "--allow", "B"]);
+    assert!(err.message.contains("value \"B\" for argument 'allow' doesn't match the pattern"));
+}
+
+#[test]
+fn lengths_are_checked() {
+    let err = error(vec!["listen", "--tag", "ab"]);
+    assert!(err.message.contains("is shorter than 3 characters"));
+    let err = error(vec!["listen", "--tag", "abcdefghi"]);
+    assert!(err.message.contains("is longer than 8 characters"));
+}
+
+#[test]
+fn validators_see_the_parsed_value() {
+    let err = error(vec!["listen", "--workers", "3"]);
+    assert!(err.message.contains("invalid value \"3\" for argument 'workers': must be even"));
+}
+
+#[test]
+fn range_and_pattern_are_in_help() {
+    let mut help = Vec::new();
+    Listen::command().write_help(&mut help).unwrap();
+    let help = String::from_utf8(help).unwrap();
+    assert!(help.contains("[range: 1..=65535]"));
+    assert!(help.contains("[pattern: ^[a-z][a-z0-9.-]*$]"));
+}
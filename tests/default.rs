@@ -0,0 +1,81 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use std::sync::atomic::{ AtomicUsize, Ordering, ATOMIC_USIZE_INIT };
+
+use stomp::{ ParseApp, StompCommand };
+
+#[derive(StompMode, Debug, PartialEq)]
+enum Format {
+    #[stomp(default)]
+    Text,
+    Json,
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+struct Show {
+    #[stomp(short = 'a')]
+    all: bool,
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+struct Push {
+    force: bool,
+}
+
+#[derive(StompCommands, Debug, PartialEq)]
+enum Commands {
+    Show(Show),
+    Push(Push),
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+#[stomp(name = "app", impl_default)]
+struct App {
+    #[stomp(default_value = "3")]
+    level: Option<u32>,
+    #[stomp(default_value = "4")]
+    jobs: u32,
+    name: Option<String>,
+    #[stomp(mode)]
+    format: Format,
+    #[stomp(subcommand, default = "show")]
+    command: Commands,
+}
+
+#[test]
+fn default_matches_empty_parse() {
+    let parsed = App::try_parse_from(vec!["app"]).unwrap();
+    assert_eq!(App::default(), parsed);
+    assert_eq!(parsed.level, Some(3));
+    assert_eq!(parsed.format, Format::Text);
+    assert_eq!(parsed.command, Commands::Show(Show { all: false }));
+}
+
+static DEFAULT_CALLS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn default_jobs() -> u32 {
+    DEFAULT_CALLS.fetch_add(1, Ordering::SeqCst);
+    4
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "build")]
+struct Build {
+    #[stomp(default_fn = "default_jobs")]
+    jobs: u32,
+}
+
+#[test]
+fn typed_default_help_is_built_once() {
+    let first = Build::command();
+    let second = Build::command();
+    drop((first, second));
+    assert_eq!(DEFAULT_CALLS.load(Ordering::SeqCst), 1);
+}
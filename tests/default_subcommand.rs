@@ -0,0 +1,120 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use std::ffi::OsString;
+
+use stomp::ParseApp;
+
+#[derive(StompCommand, Debug, PartialEq)]
+#[stomp(group(name = "target", required))]
+struct Fetch {
+    #[stomp(group = "target")]
+    file: Option<String>,
+    #[stomp(group = "target")]
+    url: Option<String>,
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+struct Status {
+    verbose: bool,
+}
+
+#[derive(StompCommands, Debug, PartialEq)]
+enum Commands {
+    Fetch(Fetch),
+    Status(Status),
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+#[stomp(name = "app")]
+struct App {
+    #[stomp(subcommand, default = "fetch")]
+    command: Commands,
+}
+
+#[test]
+fn bare_binary_checks_default_groups() {
+    let err = App::try_parse_from(vec!["app"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::MissingRequiredArgument);
+}
+
+#[test]
+fn default_groups_are_satisfied_by_merged_args() {
+    let parsed = App::try_parse_from(vec!["app", "--url", "x"]).unwrap();
+    assert_eq!(parsed.command, Commands::Fetch(Fetch { file: None, url: Some("x".to_owned()) }));
+}
+
+#[test]
+fn other_subcommands_ignore_default_groups() {
+    let parsed = App::try_parse_from(vec!["app", "status"]).unwrap();
+    assert_eq!(parsed.command, Commands::Status(Status { verbose: false }));
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+struct Copy {
+    #[stomp(index = 1)]
+    source: Option<String>,
+}
+
+#[derive(StompCommands, Debug, PartialEq)]
+enum Positional {
+    Copy(Copy),
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+#[stomp(name = "clash")]
+struct Clash {
+    #[stomp(index = 1)]
+    path: Option<String>,
+    #[stomp(subcommand, default = "copy")]
+    command: Positional,
+}
+
+#[test]
+#[should_panic(expected = "argument 'source' of default subcommand 'copy' clashes with 'path' of its parent")]
+fn default_positional_index_clash() {
+    let _ = Clash::try_parse_from(vec!["clash"]);
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+struct Start {
+    verbose: bool,
+    #[stomp(unknown)]
+    rest: Vec<OsString>,
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+struct Stop {
+}
+
+#[derive(StompCommands, Debug, PartialEq)]
+enum Actions {
+    Start(Start),
+    Stop(Stop),
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+#[stomp(name = "tool")]
+struct Tool {
+    #[stomp(subcommand, default = "start")]
+    action: Actions,
+}
+
+#[test]
+fn default_subcommand_collects_unknown_options() {
+    let tool = Tool::try_parse_from(vec!["tool", "--verbose", "--extra=1"]).unwrap();
+    assert_eq!(tool.action, Actions::Start(Start { verbose: true, rest: vec![OsString::from("--extra=1")] }));
+}
+
+#[test]
+fn unknown_options_are_errors_for_other_subcommands() {
+    assert_eq!(Tool::try_parse_from(vec!["tool", "stop"]).unwrap().action, Actions::Stop(Stop {}));
+    let err = Tool::try_parse_from(vec!["tool", "--extra", "stop"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::UnknownArgument);
+}
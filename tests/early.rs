@@ -0,0 +1,62 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use std::collections::HashMap;
+
+use stomp::{Context, ParseApp};
+
+#[derive(StompCommand, Debug)]
+struct Build {
+    #[stomp(early)]
+    config: Option<String>,
+}
+
+#[derive(StompCommands, Debug)]
+enum Commands {
+    Build(Build),
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "app")]
+struct App {
+    #[stomp(early)]
+    config: Option<String>,
+    #[stomp(early, counted)]
+    verbose: u64,
+    #[stomp(early, default_value = "2", env = "APP_JOBS")]
+    jobs: u32,
+    #[stomp(subcommand)]
+    command: Commands,
+}
+
+#[test]
+fn early_arguments_are_typed() {
+    let early = App::try_parse_early_from(vec!["app", "--verbose", "--config", "a.toml", "--verbose", "build"]).unwrap();
+    assert_eq!(early.config, Some("a.toml".to_owned()));
+    assert_eq!(early.verbose, 2);
+    assert_eq!(early.jobs, 2);
+}
+
+#[test]
+fn early_arguments_of_subcommands_are_ignored() {
+    let early = App::try_parse_early_from(vec!["app", "--jobs", "4", "build", "--config", "b.toml"]).unwrap();
+    assert_eq!(early.config, None);
+    assert_eq!(early.jobs, 4);
+}
+
+#[test]
+fn early_arguments_fall_back_to_env() {
+    let mut env = HashMap::new();
+    env.insert("APP_JOBS".to_owned(), "8".to_owned());
+    let ctx = Context::with_env(env);
+    let early = App::try_parse_early_from_with(vec!["app", "build"], &ctx).unwrap();
+    assert_eq!(early.jobs, 8);
+    let early = App::try_parse_early_from_with(vec!["app", "--jobs", "3", "build"], &ctx).unwrap();
+    assert_eq!(early.jobs, 3);
+}
@@ -0,0 +1,74 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use std::collections::HashMap;
+
+use stomp::ParseApp;
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "app")]
+struct App {
+    #[stomp(env = "TOKEN")]
+    token: Option<String>,
+    #[stomp(required_unless = "token")]
+    user: Option<String>,
+    #[stomp(env = "APP_PASSWORD")]
+    password: Option<String>,
+    #[stomp(requires = "password")]
+    login: Option<String>,
+    #[stomp(env = "QUIET")]
+    quiet: bool,
+    #[stomp(conflicts_with = "quiet")]
+    loud: bool,
+}
+
+fn parse(args: Vec<&str>, vars: &[(&str, &str)]) -> clap::Result<App> {
+    let env: HashMap<String, String> = vars.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect();
+    App::try_parse_from_with_env(args, env)
+}
+
+#[test]
+fn required_unless_counts_the_environment() {
+    let app = parse(vec!["app"], &[("TOKEN", "x")]).unwrap();
+    assert_eq!(app.token, Some("x".to_owned()));
+    assert_eq!(app.user, None);
+
+    let err = parse(vec!["app"], &[]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::MissingRequiredArgument);
+}
+
+#[test]
+fn requires_counts_the_environment() {
+    let app = parse(vec!["app", "--user", "u", "--login", "l"], &[("APP_PASSWORD", "p")]).unwrap();
+    assert_eq!(app.login, Some("l".to_owned()));
+
+    let err = parse(vec!["app", "--user", "u", "--login", "l"], &[]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::MissingRequiredArgument);
+}
+
+#[test]
+fn conflicts_count_the_environment() {
+    let err = parse(vec!["app", "--user", "u", "--loud"], &[("QUIET", "1")]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::ArgumentConflict);
+
+    let app = parse(vec!["app", "--user", "u", "--loud"], &[("QUIET", "0")]).unwrap();
+    assert!(app.loud && !app.quiet);
+}
+
+#[test]
+fn flags_accept_truthy_values() {
+    for value in &["1", "true", "YES", "on"] {
+        assert!(parse(vec!["app", "--user", "u"], &[("QUIET", value)]).unwrap().quiet);
+    }
+    for value in &["0", "false", "no", "off"] {
+        assert!(!parse(vec!["app", "--user", "u"], &[("QUIET", value)]).unwrap().quiet);
+    }
+    let err = parse(vec!["app", "--user", "u"], &[("QUIET", "maybe")]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::ValueValidation);
+}
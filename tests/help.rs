@@ -0,0 +1,68 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use std::path::PathBuf;
+
+use stomp::StompCommand;
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "build")]
+struct Build {
+    /// Where to write the output
+    zeta_path: Option<PathBuf>,
+    /// How many jobs to run
+    alpha_jobs: Option<u32>,
+    /// Who to build as
+    user_name: Option<String>,
+    /// The toolchain to use
+    #[stomp(value_name = "CHANNEL")]
+    toolchain: Option<String>,
+    /// Skips every check that would otherwise be run before the build, along with the ones that would be run
+    /// after it, and the ones in between that nobody remembers adding
+    #[stomp(help_heading = "Dangerous")]
+    skip_checks: bool,
+}
+
+fn help() -> String {
+    let mut help = Vec::new();
+    Build::command().write_help(&mut help).unwrap();
+    String::from_utf8(help).unwrap()
+}
+
+#[test]
+fn value_names_hint_at_the_type() {
+    let help = help();
+    assert!(help.contains("--zeta-path <FILE>"));
+    assert!(help.contains("--alpha-jobs <N>"));
+    assert!(help.contains("--user-name <USER_NAME>"));
+    assert!(help.contains("--toolchain <CHANNEL>"));
+}
+
+#[test]
+fn arguments_are_shown_in_field_order() {
+    let help = help();
+    assert!(help.find("--zeta-path").unwrap() < help.find("--alpha-jobs").unwrap());
+    assert!(help.find("--alpha-jobs").unwrap() < help.find("--user-name").unwrap());
+}
+
+#[test]
+fn headings_wrap_within_the_width() {
+    let help = help();
+    let heading = &help[help.find("DANGEROUS:").expect("missing heading")..];
+    let lines: Vec<_> = heading.lines().skip(1).take_while(|line| !line.is_empty()).collect();
+    assert!(lines.len() > 1);
+    assert!(lines[0].trim_left().starts_with("--skip-checks"));
+    let indent = lines[0].find("Skips").unwrap();
+    for line in &lines {
+        assert!(line.chars().count() <= 120, "line too long: {:?}", line);
+    }
+    for line in &lines[1..] {
+        assert_eq!(line.len() - line.trim_left().len(), indent);
+    }
+}
@@ -0,0 +1,90 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use stomp::{ ParseApp, StompCommand, StompMode };
+
+#[derive(StompMode, Debug, PartialEq)]
+enum Action {
+    #[stomp(default)]
+    Apply,
+    DryRun,
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "app")]
+struct App {
+    #[stomp(mode)]
+    action: Action,
+}
+
+#[test]
+fn variants_are_kebab_cased() {
+    assert_eq!(Action::names(), vec!["apply", "dry-run"]);
+    let app = App::try_parse_from(vec!["app", "--dry-run"]).unwrap();
+    assert_eq!(app.action, Action::DryRun);
+    assert!(App::try_parse_from(vec!["app", "--dryrun"]).is_err());
+}
+
+#[derive(StompMode, Debug, PartialEq)]
+#[stomp(rename_all = "snake_case")]
+enum Snake {
+    #[stomp(default)]
+    KeepGoing,
+}
+
+#[test]
+fn variants_follow_rename_all() {
+    assert_eq!(Snake::names(), vec!["keep_going"]);
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "clash")]
+struct Clash {
+    dry_run: bool,
+    #[stomp(mode)]
+    action: Action,
+}
+
+#[test]
+#[should_panic(expected = "argument 'dry-run' of mode 'action' clashes with another argument of the command")]
+fn mode_arguments_clash_with_fields() {
+    Clash::command();
+}
+
+#[derive(StompMode, Debug, PartialEq)]
+enum Test {
+    Name(String),
+    Type(String),
+    #[stomp(short = 'o')]
+    Or,
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "find")]
+struct Find {
+    #[stomp(sequence)]
+    tests: Vec<Test>,
+}
+
+#[test]
+fn sequences_keep_their_order() {
+    let find = Find::try_parse_from(vec!["find", "--type", "d", "-o", "--name", "x"]).unwrap();
+    assert_eq!(find.tests, vec![Test::Type("d".to_owned()), Test::Or, Test::Name("x".to_owned())]);
+}
+
+#[test]
+fn sequences_take_single_dash_longs() {
+    let find = Find::try_parse_from(vec!["find", "-name", "x", "-o", "-type", "f", "-name=y"]).unwrap();
+    assert_eq!(find.tests, vec![
+        Test::Name("x".to_owned()),
+        Test::Or,
+        Test::Type("f".to_owned()),
+        Test::Name("y".to_owned()),
+    ]);
+}
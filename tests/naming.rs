@@ -0,0 +1,62 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use stomp::ParseApp;
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "kebab")]
+struct Kebab {
+    debug_level: Option<u32>,
+    #[stomp(long = "out")]
+    output_file: Option<String>,
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "snake", rename_all = "snake_case")]
+struct Snake {
+    debug_level: Option<u32>,
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "copy")]
+struct Copy {
+    #[stomp(arg)]
+    from: String,
+    #[stomp(index = 1)]
+    mode: String,
+    #[stomp(arg)]
+    to: String,
+}
+
+#[test]
+fn longs_are_kebab_case_by_default() {
+    let kebab = Kebab::try_parse_from(vec!["kebab", "--debug-level", "2", "--out", "x"]).unwrap();
+    assert_eq!(kebab.debug_level, Some(2));
+    assert_eq!(kebab.output_file, Some("x".to_owned()));
+
+    let err = Kebab::try_parse_from(vec!["kebab", "--debug_level", "2"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::UnknownArgument);
+}
+
+#[test]
+fn rename_all_changes_the_longs() {
+    let snake = Snake::try_parse_from(vec!["snake", "--debug_level", "2"]).unwrap();
+    assert_eq!(snake.debug_level, Some(2));
+
+    let err = Snake::try_parse_from(vec!["snake", "--debug-level", "2"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::UnknownArgument);
+}
+
+#[test]
+fn positionals_are_indexed_around_given_indexes() {
+    let copy = Copy::try_parse_from(vec!["copy", "fast", "a", "b"]).unwrap();
+    assert_eq!(copy.mode, "fast");
+    assert_eq!(copy.from, "a");
+    assert_eq!(copy.to, "b");
+}
@@ -0,0 +1,37 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use std::ffi::OsString;
+
+use stomp::ParseApp;
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "exec")]
+struct Exec {
+    verbose: bool,
+    #[stomp(raw)]
+    command: Vec<String>,
+}
+
+#[test]
+fn raw_values_are_kept_verbatim() {
+    let exec = Exec::try_parse_from(vec!["exec", "--verbose", "ls", "-la", "--color"]).unwrap();
+    assert!(exec.verbose);
+    assert_eq!(exec.command, vec!["ls", "-la", "--color"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn raw_values_that_arent_unicode_are_an_error() {
+    use std::os::unix::ffi::OsStringExt;
+
+    let args = vec![OsString::from("exec"), OsString::from("ls"), OsString::from_vec(vec![0x66, 0xff])];
+    let err = Exec::try_parse_from(args).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::InvalidUtf8);
+}
@@ -0,0 +1,58 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use stomp::ParseApp;
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "deploy", group(name = "target", required))]
+struct Deploy {
+    #[stomp(group = "target")]
+    host: Option<String>,
+    #[stomp(group = "target")]
+    cluster: Option<String>,
+    #[stomp(conflicts_with = "assume_yes")]
+    dry_run: bool,
+    #[stomp(name = "yes")]
+    assume_yes: bool,
+    #[stomp(requires = "key_file")]
+    user: Option<String>,
+    key_file: Option<String>,
+}
+
+#[test]
+fn required_group_is_enforced() {
+    let err = Deploy::try_parse_from(vec!["deploy"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::MissingRequiredArgument);
+}
+
+#[test]
+fn group_members_conflict() {
+    let err = Deploy::try_parse_from(vec!["deploy", "--host", "a", "--cluster", "b"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::ArgumentConflict);
+}
+
+#[test]
+fn conflicts_with_resolves_renamed_field() {
+    let err = Deploy::try_parse_from(vec!["deploy", "--host", "a", "--dry-run", "--yes"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::ArgumentConflict);
+
+    let deploy = Deploy::try_parse_from(vec!["deploy", "--host", "a", "--dry-run"]).unwrap();
+    assert!(deploy.dry_run);
+    assert!(!deploy.assume_yes);
+}
+
+#[test]
+fn requires_resolves_field_name() {
+    let err = Deploy::try_parse_from(vec!["deploy", "--cluster", "b", "--user", "u"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::MissingRequiredArgument);
+
+    let deploy = Deploy::try_parse_from(vec!["deploy", "--cluster", "b", "--user", "u", "--key-file", "k"]).unwrap();
+    assert_eq!(deploy.cluster, Some("b".to_owned()));
+    assert_eq!(deploy.key_file, Some("k".to_owned()));
+}
@@ -0,0 +1,107 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use std::error::Error;
+use std::thread;
+
+use stomp::{ ParseApp, Run };
+
+#[derive(StompCommand, Debug)]
+struct Build {
+    fail: bool,
+}
+
+#[derive(StompCommand, Debug)]
+struct Clean {
+}
+
+#[derive(StompCommands, Debug)]
+#[stomp(run(context = "Vec<String>", error = "String", before = "before", after = "after"))]
+enum Commands {
+    Build(Build),
+    Clean(Clean),
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "app")]
+struct App {
+    #[stomp(subcommand)]
+    command: Commands,
+}
+
+impl Run<Vec<String>> for Build {
+    type Err = String;
+    fn run(self, log: &mut Vec<String>) -> Result<(), String> {
+        log.push("build".to_owned());
+        if self.fail { Err("build failed".to_owned()) } else { Ok(()) }
+    }
+}
+
+impl Run<Vec<String>> for Clean {
+    type Err = String;
+    fn run(self, log: &mut Vec<String>) -> Result<(), String> {
+        log.push("clean".to_owned());
+        Ok(())
+    }
+}
+
+fn before(log: &mut Vec<String>) -> Result<(), String> {
+    log.push("before".to_owned());
+    Ok(())
+}
+
+fn after(log: &mut Vec<String>, result: Result<(), String>) -> Result<(), String> {
+    log.push(format!("after {:?}", result));
+    result
+}
+
+fn run(args: Vec<&str>) -> (Vec<String>, Result<(), String>) {
+    let app = App::try_parse_from(args).unwrap();
+    let mut log = Vec::new();
+    let result = app.command.run(&mut log);
+    (log, result)
+}
+
+#[test]
+fn runs_the_variant_between_hooks() {
+    let (log, result) = run(vec!["app", "clean"]);
+    assert_eq!(result, Ok(()));
+    assert_eq!(log, vec!["before", "clean", "after Ok(())"]);
+}
+
+#[test]
+fn after_hook_sees_the_error() {
+    let (log, result) = run(vec!["app", "build", "--fail"]);
+    assert_eq!(result, Err("build failed".to_owned()));
+    assert_eq!(log, vec!["before", "build", "after Err(\"build failed\")"]);
+}
+
+#[derive(StompCommand, Debug)]
+struct Check {
+}
+
+#[derive(StompCommands, Debug)]
+#[stomp(run)]
+enum Plain {
+    Check(Check),
+}
+
+impl Run<()> for Check {
+    type Err = Box<Error + Send + Sync>;
+    fn run(self, _: &mut ()) -> Result<(), Self::Err> {
+        Err("check failed".into())
+    }
+}
+
+#[test]
+fn default_error_can_be_sent_between_threads() {
+    let command = Plain::Check(Check {});
+    let result = thread::spawn(move || command.run(&mut ())).join().unwrap();
+    assert_eq!(result.unwrap_err().to_string(), "check failed");
+}
@@ -0,0 +1,54 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use stomp::ParseApp;
+
+#[derive(StompMode, Debug, PartialEq)]
+enum Format {
+    #[stomp(default)]
+    Text,
+    #[stomp(short = 'j')]
+    Json,
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+struct Show {
+    #[stomp(short)]
+    xml: bool,
+}
+
+#[derive(StompCommands, Debug, PartialEq)]
+enum Commands {
+    #[stomp(help_short = 'x')]
+    Show(Show),
+}
+
+#[derive(StompCommand, Debug, PartialEq)]
+#[stomp(name = "app")]
+struct App {
+    #[stomp(mode)]
+    format: Format,
+    #[stomp(short)]
+    json_lines: bool,
+    #[stomp(subcommand)]
+    command: Option<Commands>,
+}
+
+#[test]
+fn shorts_avoid_mode_variants() {
+    let app = App::try_parse_from(vec!["app", "-j", "-J"]).unwrap();
+    assert_eq!(app.format, Format::Json);
+    assert!(app.json_lines);
+}
+
+#[test]
+fn shorts_avoid_variant_help_short() {
+    let app = App::try_parse_from(vec!["app", "show", "-X"]).unwrap();
+    assert_eq!(app.command, Some(Commands::Show(Show { xml: true })));
+}
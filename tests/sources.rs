@@ -0,0 +1,53 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use std::collections::HashMap;
+
+use stomp::{ Context, ParseApp, Source };
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "app", explain_config, sources)]
+struct App {
+    #[stomp(default_value = "3")]
+    level: u32,
+    #[stomp(env = "APP_NAME")]
+    name: String,
+}
+
+#[test]
+fn sources_are_derived() {
+    let mut env = HashMap::new();
+    env.insert("APP_NAME".to_owned(), "x".to_owned());
+    let (app, sources) = App::try_parse_from_with_sources(vec!["app"], &Context::with_env(env)).unwrap();
+    assert_eq!(app.name, "x");
+    assert_eq!(sources.level, Source::Default);
+    assert_eq!(sources.name, Source::Environment("APP_NAME".to_owned()));
+}
+
+#[test]
+fn explain_config_ignores_missing_values() {
+    let err = App::try_parse_from_with(vec!["app", "--explain-config"], &Context::with_env(HashMap::new())).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::HelpDisplayed);
+    assert!(err.message.contains("level = 3 (default)"));
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "plain")]
+struct Plain {
+    #[stomp(env = "PLAIN_NAME")]
+    name: String,
+}
+
+#[test]
+fn sources_without_opting_in_are_untyped() {
+    let mut env = HashMap::new();
+    env.insert("PLAIN_NAME".to_owned(), "x".to_owned());
+    let (_, sources): (Plain, stomp::Sources) = Plain::try_parse_from_with_sources(vec!["plain"], &Context::with_env(env)).unwrap();
+    assert_eq!(sources.get(&[], "name").map(|entry| entry.source.clone()), Some(Source::Environment("PLAIN_NAME".to_owned())));
+}
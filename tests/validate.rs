@@ -0,0 +1,78 @@
+#![feature(attr_literals)]
+#![feature(custom_derive)]
+#![feature(proc_macro)]
+
+extern crate clap;
+extern crate stomp;
+#[macro_use]
+extern crate stomp_macros;
+
+use stomp::ParseApp;
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "serve", validate = "check_serve")]
+struct Serve {
+    min_workers: Option<u32>,
+    max_workers: Option<u32>,
+}
+
+fn check_serve(serve: &mut Serve) -> Result<(), String> {
+    if serve.max_workers.is_none() {
+        serve.max_workers = serve.min_workers;
+    }
+    match (serve.min_workers, serve.max_workers) {
+        (Some(min), Some(max)) if min > max => Err(format!("--min-workers {} is more than --max-workers {}", min, max)),
+        _ => Ok(()),
+    }
+}
+
+#[derive(StompCommand, Debug)]
+struct Copy {
+    #[stomp(index = 1)]
+    from: String,
+    #[stomp(index = 2)]
+    to: String,
+}
+
+fn check_copy(copy: &mut Copy) -> Result<(), String> {
+    if copy.from == copy.to {
+        Err("can't copy a file onto itself".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(StompCommands, Debug)]
+enum Commands {
+    #[stomp(validate = "check_copy")]
+    Copy(Copy),
+}
+
+#[derive(StompCommand, Debug)]
+#[stomp(name = "app")]
+struct App {
+    #[stomp(subcommand)]
+    command: Commands,
+}
+
+#[test]
+fn validate_can_fix_up_the_command() {
+    let serve = Serve::try_parse_from(vec!["serve", "--min-workers", "4"]).unwrap();
+    assert_eq!(serve.max_workers, Some(4));
+}
+
+#[test]
+fn validate_errors_are_usage_errors() {
+    let err = Serve::try_parse_from(vec!["serve", "--min-workers", "4", "--max-workers", "2"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::ValueValidation);
+    assert!(err.message.contains("--min-workers 4 is more than --max-workers 2"));
+}
+
+#[test]
+fn validate_runs_on_subcommands() {
+    App::try_parse_from(vec!["app", "copy", "a", "b"]).unwrap();
+
+    let err = App::try_parse_from(vec!["app", "copy", "a", "a"]).unwrap_err();
+    assert_eq!(err.kind, clap::ErrorKind::ValueValidation);
+    assert!(err.message.contains("can't copy a file onto itself"));
+}